@@ -24,4 +24,6 @@ fn main() {
     // pass the disk image paths as env variables to the `main.rs`
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
+    // the runner creates images again when it's given a kernel command line
+    println!("cargo:rustc-env=KERNEL_PATH={}", os.display());
 }
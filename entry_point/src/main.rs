@@ -3,20 +3,18 @@
 
 extern crate alloc;
 use alloc::boxed::Box;
-use bootloader_api::{BootInfo, BootloaderConfig, config::Mapping, entry_point};
+use bootloader_api::entry_point;
 
-pub static BOOTLOADER_CONFIG: BootloaderConfig = {
-    let mut config = BootloaderConfig::new_default();
-
-    config.mappings.physical_memory = Some(Mapping::Dynamic);
-    config
-};
-
-entry_point!(entry_point, config = &BOOTLOADER_CONFIG);
+entry_point!(entry_point, config = &kernel::BOOTLOADER_CONFIG);
 fn entry_point(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
     kernel::init_kernel(boot_info);
     os::init_os();
-    let terminal = terminal::Terminal::new();
-    os::run_app(Box::new(terminal));
+    // `app=<name>` boot option, the serial console runs either way
+    match os::boot_config().app.unwrap_or("terminal") {
+        "terminal" => os::run_app(Box::new(terminal::Terminal::new())),
+        "none" => {}
+        app => log::warn!("unknown app: {app:?}, available: terminal, none"),
+    }
+    os::exec_async_task(terminal::run_serial_console());
     kernel::start_task_executor_loop();
 }
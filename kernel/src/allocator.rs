@@ -11,17 +11,20 @@ use log::debug;
 
 use core::ptr::null_mut;
 
+use crate::sync::{IrqSafeMutex, IrqSafeMutexGuard};
+
+// interrupt handlers allocate too (eg. wakers pushing to run queues)
 pub struct Locked<T> {
-    inner: spin::Mutex<T>,
+    inner: IrqSafeMutex<T>,
 }
 impl<T> Locked<T> {
     pub const fn new(inner: T) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: IrqSafeMutex::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<T> {
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
         self.inner.lock()
     }
 }
@@ -158,3 +161,43 @@ impl ReallyBigTestStruct {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn simple_allocation() {
+        let first = Box::new(41);
+        let second = Box::new(13);
+        assert_eq!(*first, 41);
+        assert_eq!(*second, 13);
+    }
+
+    #[test_case]
+    fn large_vec() {
+        let n = 1000;
+        let vec: Vec<u64> = (0..n).collect();
+        assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+    }
+
+    #[test_case]
+    fn freed_memory_is_reused() {
+        // would run out of heap if nothing was freed
+        for i in 0..HEAP_SIZE / 64 {
+            let x = Box::new(i);
+            assert_eq!(*x, i);
+        }
+    }
+
+    #[test_case]
+    fn big_structs() {
+        let structs: Vec<ReallyBigTestStruct> =
+            (0..10).map(|_| ReallyBigTestStruct::new()).collect();
+        assert!(
+            structs
+                .iter()
+                .all(|test| test.vec.len() == 100 && test.array2[0] == 20)
+        );
+    }
+}
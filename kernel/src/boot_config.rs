@@ -0,0 +1,231 @@
+//! Boot time configuration, `key=value` options separated by spaces (eg. `log=info smp=off`).
+//!
+//! Options come from two places: `KERNEL_CMDLINE` embedded at build time and the bootloader's
+//! ramdisk, where the runner puts its `--cmdline`. The runner's come later, so they override the
+//! embedded ones. Everything is parsed first in `init_kernel`, before the heap exists.
+//!
+//! Options known to the kernel:
+//! - `log=<directives>` log levels per target, see `logger::LogFilter::apply`
+//! - `serial=<config>` COM1 line settings, eg. `9600,7E2`, see `serial::LineConfig`
+//! - `gdb` / `gdb=wait` enables the gdb stub on COM2, `wait` stops at the end of `init_kernel`
+//! - `smp=on|off` starts the other cpus (default: on)
+//! - `cpus=<n>` uses at most `n` cpus, including the bootstrap processor
+//! - `app=<name>` which app `entry_point` launches
+//!
+//! Other options are kept as they are, anything can read them with `BootConfig::option`.
+
+use conquer_once::spin::OnceCell;
+use log::*;
+
+use crate::serial::LineConfig;
+
+/// Embedded at build time, `KERNEL_CMDLINE="log=debug gdb" cargo run`
+pub const EMBEDDED_CMDLINE: &str = match option_env!("KERNEL_CMDLINE") {
+    Some(cmdline) => cmdline,
+    None => "",
+};
+
+const MAX_INVALID_OPTIONS: usize = 8;
+
+static CONFIG: OnceCell<BootConfig> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GdbMode {
+    #[default]
+    Off,
+    On,
+    /// stop at the end of `init_kernel` until gdb attaches
+    Wait,
+}
+
+#[derive(Debug)]
+pub struct BootConfig {
+    /// `log` directives, applied on top of the default level
+    pub log: Option<&'static str>,
+    pub serial: LineConfig,
+    pub gdb: GdbMode,
+    pub smp: bool,
+    pub max_cpus: Option<usize>,
+    pub app: Option<&'static str>,
+
+    embedded: &'static str,
+    runner: &'static str,
+    // logged once the logger works
+    invalid: heapless::Vec<&'static str, MAX_INVALID_OPTIONS>,
+}
+
+impl BootConfig {
+    /// Options in `runner` override the ones in `embedded`.
+    /// Invalid values are skipped, so a typo doesn't stop the kernel from booting.
+    pub fn parse(embedded: &'static str, runner: &'static str) -> BootConfig {
+        let mut config = BootConfig {
+            log: None,
+            serial: LineConfig::default(),
+            gdb: GdbMode::Off,
+            smp: true,
+            max_cpus: None,
+            app: None,
+            embedded,
+            runner,
+            invalid: heapless::Vec::new(),
+        };
+
+        for option in embedded.split_whitespace().chain(runner.split_whitespace()) {
+            let (key, value) = option.split_once('=').unwrap_or((option, ""));
+            let valid = match key {
+                "log" => {
+                    config.log = Some(value);
+                    true
+                }
+                "serial" => value.parse().map(|serial| config.serial = serial).is_ok(),
+                "gdb" => match value {
+                    "" | "on" => {
+                        config.gdb = GdbMode::On;
+                        true
+                    }
+                    "wait" => {
+                        config.gdb = GdbMode::Wait;
+                        true
+                    }
+                    "off" => {
+                        config.gdb = GdbMode::Off;
+                        true
+                    }
+                    _ => false,
+                },
+                "smp" => parse_bool(value).map(|smp| config.smp = smp).is_some(),
+                "cpus" => match value.parse() {
+                    Ok(cpus) if cpus > 0 => {
+                        config.max_cpus = Some(cpus);
+                        true
+                    }
+                    _ => false,
+                },
+                "app" => {
+                    config.app = Some(value);
+                    true
+                }
+                // not known to the kernel, read with `option`
+                _ => true,
+            };
+            if !valid {
+                let _ = config.invalid.push(option);
+            }
+        }
+        config
+    }
+
+    /// Value of the last option with this key, options without `=` have an empty value
+    pub fn option(&self, key: &str) -> Option<&'static str> {
+        find_option(self.runner, key).or_else(|| find_option(self.embedded, key))
+    }
+
+    /// How many cpus can be started, including the bootstrap processor
+    pub fn usable_cpus(&self, available: usize) -> usize {
+        if !self.smp {
+            return 1;
+        }
+        self.max_cpus.map_or(available, |max| max.min(available))
+    }
+
+    fn log_summary(&self) {
+        info!(
+            "boot config: embedded: {:?}, from runner: {:?}",
+            self.embedded, self.runner
+        );
+        for option in &self.invalid {
+            warn!("ignoring invalid boot option: {option:?}");
+        }
+    }
+}
+
+fn find_option(cmdline: &'static str, key: &str) -> Option<&'static str> {
+    cmdline
+        .split_whitespace()
+        .rev()
+        .find_map(|option| match option.split_once('=') {
+            Some((name, value)) => (name == key).then_some(value),
+            None => (option == key).then_some(""),
+        })
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "" | "on" | "true" | "1" => Some(true),
+        "off" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses the embedded options and the ones the runner put into the ramdisk.
+/// Has to be called first in `init_kernel`, everything after it can read the config.
+pub fn init(boot_info: &bootloader_api::BootInfo) {
+    CONFIG.init_once(|| BootConfig::parse(EMBEDDED_CMDLINE, ramdisk_cmdline(boot_info)));
+}
+
+fn ramdisk_cmdline(boot_info: &bootloader_api::BootInfo) -> &'static str {
+    let Some(address) = boot_info.ramdisk_addr.into_option() else {
+        return "";
+    };
+    // mapped by the bootloader and never freed
+    let ramdisk = unsafe {
+        core::slice::from_raw_parts(address as *const u8, boot_info.ramdisk_len as usize)
+    };
+    core::str::from_utf8(ramdisk).unwrap_or("")
+}
+
+/// Logs the options and the invalid ones, once the logger works
+pub(crate) fn log_summary() {
+    get().log_summary();
+}
+
+pub fn get() -> &'static BootConfig {
+    CONFIG
+        .get()
+        .expect("boot config is parsed first in init_kernel")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn defaults() {
+        let config = BootConfig::parse("", "");
+        assert_eq!(config.gdb, GdbMode::Off);
+        assert!(config.smp);
+        assert_eq!(config.usable_cpus(4), 4);
+        assert_eq!(config.log, None);
+    }
+
+    #[test_case]
+    fn runner_overrides_embedded() {
+        let config = BootConfig::parse("log=debug smp=off custom=1", "log=info smp=on");
+        assert_eq!(config.log, Some("info"));
+        assert!(config.smp);
+        assert_eq!(config.option("custom"), Some("1"));
+        assert_eq!(config.option("log"), Some("info"));
+        assert_eq!(config.option("missing"), None);
+    }
+
+    #[test_case]
+    fn typed_options() {
+        let config = BootConfig::parse("gdb=wait cpus=2 serial=9600,7E2 app=none flag", "");
+        assert_eq!(config.gdb, GdbMode::Wait);
+        assert_eq!(config.usable_cpus(4), 2);
+        assert_eq!(config.usable_cpus(1), 1);
+        assert_eq!(config.serial.baud_rate, 9600);
+        assert_eq!(config.app, Some("none"));
+        assert_eq!(config.option("flag"), Some(""));
+        assert!(config.invalid.is_empty());
+    }
+
+    #[test_case]
+    fn invalid_options_are_skipped() {
+        let config = BootConfig::parse("cpus=0 smp=maybe gdb=later serial=fast", "");
+        assert_eq!(config.invalid.len(), 4);
+        assert!(config.smp);
+        assert_eq!(config.gdb, GdbMode::Off);
+        assert_eq!(config.usable_cpus(4), 4);
+    }
+}
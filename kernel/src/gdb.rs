@@ -0,0 +1,254 @@
+//! GDB remote serial protocol stub on COM2.
+//!
+//! Enabled with the `gdb` boot option, `gdb=wait` also stops the kernel at the end of
+//! `init_kernel` until a debugger attaches. Connect with `target remote` to QEMU's second
+//! `-serial`. Every cpu is stopped while gdb is in control: the one that trapped talks to gdb,
+//! the rest get an NMI and wait in `park`. Each cpu is shown as a thread.
+
+mod memory;
+mod packet;
+mod stub;
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use log::*;
+
+use crate::{
+    boot_config::{self, GdbMode},
+    interrupts::{
+        ipi::{self, IpiTarget},
+        trap::{RFLAGS_TRAP, TrapFrame},
+    },
+    percpu::{self, MAX_CPUS},
+    serial::{LineConfig, Uart},
+};
+
+/// COM2, the second `-serial` in QEMU
+const COM2: Uart = unsafe { Uart::new(0x2F8) };
+
+const NO_OWNER: usize = usize::MAX;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// cpu that talks to gdb, `NO_OWNER` while the kernel runs
+static OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+// set while gdb has the kernel stopped, other cpus wait in `park` until it's cleared
+static STOPPED: AtomicBool = AtomicBool::new(false);
+// registers of every stopped cpu, null while it runs
+static FRAMES: [AtomicPtr<TrapFrame>; MAX_CPUS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+// cpus that loaded IDT, NMIs are only sent to them
+static READY: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+// NMIs sent by `stop_other_cpus`, so the handler can tell them apart from hardware ones
+static NMI_PENDING: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+// breakpoint a cpu is stepping over (0 -> none), put back by the debug exception
+static STEP_OVER: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+// whether to keep running after stepping over the breakpoint, instead of stopping
+static STEP_CONTINUE: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// Why the kernel stopped, reported to gdb as a signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StopReason {
+    Breakpoint,
+    Step,
+    /// ctrl+c or a packet from gdb while running
+    Interrupt,
+}
+
+/// How the stopped cpu continues once gdb lets it go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Resume {
+    Continue,
+    Step,
+}
+
+/// Sets up COM2 if the `gdb` boot option is set.
+/// Has to run before interrupts, COM2 IRQ is routed by `interrupts::apic::init`.
+pub fn init() {
+    if boot_config::get().gdb == GdbMode::Off {
+        return;
+    }
+    if let Err(err) = COM2.init(LineConfig::default()) {
+        error!("gdb stub disabled, couldn't configure COM2: {err:?}");
+        return;
+    }
+    ENABLED.store(true, Ordering::Release);
+    info!("gdb stub listening on COM2");
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// With `gdb=wait` stops here until gdb attaches and continues
+pub fn wait_for_debugger() {
+    if is_enabled() && boot_config::get().gdb == GdbMode::Wait {
+        info!("waiting for gdb on COM2");
+        x86_64::instructions::interrupts::int3();
+    }
+}
+
+/// Called once this cpu loaded IDT and can take NMIs
+pub(crate) fn cpu_ready() {
+    READY[percpu::this_cpu().index].store(true, Ordering::Release);
+}
+
+fn cpu_index() -> usize {
+    // breakpoints can be hit before per cpu data exists, only bootstrap processor runs then
+    percpu::try_this_cpu().map_or(0, |cpu| cpu.index)
+}
+
+/// Called by the breakpoint handler, returns false when the stub is disabled
+pub(crate) fn on_breakpoint(frame: &mut TrapFrame) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    // rip points after int3, for breakpoints set by gdb it has to point at them,
+    // so they're reported at the right place and run again after being resumed
+    let address = frame.rip - 1;
+    if stub::is_breakpoint(address) {
+        frame.rip = address;
+    }
+    enter(frame, StopReason::Breakpoint, false);
+    true
+}
+
+/// Called by the debug exception handler (single step), returns false when it wasn't caused by
+/// the stub
+pub(crate) fn on_debug(frame: &mut TrapFrame) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    let cpu = cpu_index();
+    let stepped_over = STEP_OVER[cpu].swap(0, Ordering::AcqRel);
+    if stepped_over != 0 {
+        stub::reinsert_breakpoint(stepped_over);
+        if STEP_CONTINUE[cpu].load(Ordering::Acquire) {
+            frame.rflags &= !RFLAGS_TRAP;
+            return true;
+        }
+    }
+    if frame.rflags & RFLAGS_TRAP == 0 {
+        return false;
+    }
+    frame.rflags &= !RFLAGS_TRAP;
+    enter(frame, StopReason::Step, false);
+    true
+}
+
+/// Called by the NMI handler, returns false for NMIs not sent by the stub
+pub(crate) fn on_nmi(frame: &mut TrapFrame) -> bool {
+    let cpu = cpu_index();
+    if !NMI_PENDING[cpu].swap(false, Ordering::AcqRel) {
+        return false;
+    }
+    if STOPPED.load(Ordering::Acquire) && OWNER.load(Ordering::Acquire) != cpu {
+        park(cpu, frame);
+    }
+    true
+}
+
+/// Called by the COM2 interrupt handler, gdb wants to stop the running kernel
+pub(crate) fn on_interrupt(frame: &mut TrapFrame) {
+    while let Some(byte) = COM2.try_receive() {
+        match byte {
+            packet::INTERRUPT => {
+                enter(frame, StopReason::Interrupt, false);
+                return;
+            }
+            // eg. gdb attaching, the stub reads the rest of the packet
+            b'$' => {
+                enter(frame, StopReason::Interrupt, true);
+                return;
+            }
+            // acks of the last stop reply
+            _ => {}
+        }
+    }
+}
+
+/// Stops the kernel and hands this cpu to gdb, returns once gdb resumes it
+fn enter(frame: &mut TrapFrame, reason: StopReason, packet_started: bool) {
+    let cpu = cpu_index();
+    if OWNER
+        .compare_exchange(NO_OWNER, cpu, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        // another cpu already talks to gdb, a breakpoint hit here traps again once resumed
+        park(cpu, frame);
+        return;
+    }
+    STOPPED.store(true, Ordering::Release);
+    FRAMES[cpu].store(frame, Ordering::Release);
+    stop_other_cpus(cpu);
+
+    let resume = stub::run(cpu, reason, packet_started);
+
+    // gdb could have changed rip, so check for breakpoints only now
+    let rip = frame.rip;
+    STEP_CONTINUE[cpu].store(resume == Resume::Continue, Ordering::Release);
+    if stub::is_breakpoint(rip) {
+        // WARN: other cpus can run past this breakpoint while it's removed
+        stub::remove_breakpoint_temporarily(rip);
+        STEP_OVER[cpu].store(rip, Ordering::Release);
+        frame.rflags |= RFLAGS_TRAP;
+    } else if resume == Resume::Step {
+        frame.rflags |= RFLAGS_TRAP;
+    } else {
+        frame.rflags &= !RFLAGS_TRAP;
+    }
+
+    FRAMES[cpu].store(ptr::null_mut(), Ordering::Release);
+    STOPPED.store(false, Ordering::Release);
+    OWNER.store(NO_OWNER, Ordering::Release);
+}
+
+fn stop_other_cpus(this_cpu: usize) {
+    let mut waiting_for = 0;
+    for cpu in percpu::all_cpus() {
+        let index = cpu.index;
+        if index == this_cpu
+            || !READY[index].load(Ordering::Acquire)
+            || !FRAMES[index].load(Ordering::Acquire).is_null()
+        {
+            continue;
+        }
+        NMI_PENDING[index].store(true, Ordering::Release);
+        ipi::send_nmi(IpiTarget::Cpu(index));
+        waiting_for += 1;
+    }
+
+    // TSC isn't calibrated during early boot, so just spin long enough for an NMI to arrive
+    for _ in 0..100_000_000 {
+        let parked = percpu::all_cpus()
+            .filter(|cpu| {
+                cpu.index != this_cpu && !FRAMES[cpu.index].load(Ordering::Acquire).is_null()
+            })
+            .count();
+        if parked >= waiting_for {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    warn!("gdb: not every cpu stopped");
+}
+
+/// Waits until gdb resumes the kernel, meanwhile gdb can read and change `frame`
+fn park(cpu: usize, frame: &mut TrapFrame) {
+    FRAMES[cpu].store(frame, Ordering::Release);
+    while STOPPED.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    FRAMES[cpu].store(ptr::null_mut(), Ordering::Release);
+}
+
+/// Registers of a stopped cpu
+///
+/// # Safety
+/// Only for the owner while the kernel is stopped, the cpu waits in `park` and doesn't touch it
+unsafe fn frame(cpu: usize) -> Option<&'static mut TrapFrame> {
+    let frame = FRAMES.get(cpu)?.load(Ordering::Acquire);
+    unsafe { frame.as_mut() }
+}
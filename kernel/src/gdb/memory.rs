@@ -0,0 +1,55 @@
+use x86_64::{
+    VirtAddr,
+    registers::control::{Cr0, Cr0Flags},
+    structures::paging::Translate,
+};
+
+use crate::memory::MAPPER;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Whether every page of `address..address + len` is mapped, so accessing it won't fault
+fn is_mapped(address: u64, len: usize) -> bool {
+    let Some(end) = address.checked_add(len as u64) else {
+        return false;
+    };
+    // stopped code could hold the lock, then memory can't be checked
+    let Some(mapper) = MAPPER.get().and_then(|mapper| mapper.try_lock()) else {
+        return false;
+    };
+    let mut page = address & !(PAGE_SIZE - 1);
+    while page < end {
+        let Ok(page_address) = VirtAddr::try_new(page) else {
+            return false;
+        };
+        if mapper.translate_addr(page_address).is_none() {
+            return false;
+        }
+        page += PAGE_SIZE;
+    }
+    true
+}
+
+pub fn read(address: u64, buffer: &mut [u8]) -> Result<(), ()> {
+    if !is_mapped(address, buffer.len()) {
+        return Err(());
+    }
+    for (offset, byte) in buffer.iter_mut().enumerate() {
+        *byte = unsafe { core::ptr::read_volatile((address + offset as u64) as *const u8) };
+    }
+    Ok(())
+}
+
+/// Also writes read only pages (eg. kernel code for breakpoints)
+pub fn write(address: u64, data: &[u8]) -> Result<(), ()> {
+    if !is_mapped(address, data.len()) {
+        return Err(());
+    }
+    let cr0 = Cr0::read();
+    unsafe { Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT) };
+    for (offset, byte) in data.iter().enumerate() {
+        unsafe { core::ptr::write_volatile((address + offset as u64) as *mut u8, *byte) };
+    }
+    unsafe { Cr0::write(cr0) };
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use crate::serial::Uart;
+
+/// Same as `PacketSize` in the `qSupported` reply
+pub const MAX_PACKET_SIZE: usize = 4096;
+
+pub type Packet = heapless::Vec<u8, MAX_PACKET_SIZE>;
+
+/// Byte gdb sends (outside of packets) when the user presses ctrl+c
+pub const INTERRUPT: u8 = 0x03;
+
+/// Remote serial protocol framing (`$data#checksum`) over a polled UART.
+/// Runs with interrupts disabled and never allocates, the heap could be locked by stopped code.
+pub struct Connection {
+    uart: Uart,
+}
+
+impl Connection {
+    pub const fn new(uart: Uart) -> Self {
+        Connection { uart }
+    }
+
+    fn read_byte(&self) -> u8 {
+        loop {
+            if let Some(byte) = self.uart.try_receive() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Waits for the next packet with a valid checksum, asks gdb to resend broken ones.
+    /// `started` when its `$` was already received.
+    pub fn read_packet(&self, packet: &mut Packet, mut started: bool) {
+        loop {
+            // acks and interrupts outside of packets don't matter while stopped
+            if !started {
+                while self.read_byte() != b'$' {}
+            }
+            started = false;
+
+            packet.clear();
+            let mut checksum = 0u8;
+            let mut overflow = false;
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    byte => {
+                        checksum = checksum.wrapping_add(byte);
+                        overflow |= packet.push(byte).is_err();
+                    }
+                }
+            }
+            let expected = [self.read_byte(), self.read_byte()];
+            if !overflow && decode_hex_byte(expected) == Some(checksum) {
+                self.uart.send_blocking(b'+');
+                return;
+            }
+            self.uart.send_blocking(b'-');
+        }
+    }
+
+    /// Sends `data` until gdb acknowledges it
+    pub fn send_packet(&self, data: &[u8]) {
+        loop {
+            self.uart.send_blocking(b'$');
+            let mut checksum = 0u8;
+            for byte in data {
+                self.uart.send_blocking(*byte);
+                checksum = checksum.wrapping_add(*byte);
+            }
+            self.uart.send_blocking(b'#');
+            for byte in encode_hex_byte(checksum) {
+                self.uart.send_blocking(byte);
+            }
+            // anything other than `-` (eg. ctrl+c) is skipped
+            loop {
+                match self.read_byte() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+pub fn encode_hex_byte(byte: u8) -> [u8; 2] {
+    [HEX[(byte >> 4) as usize], HEX[(byte & 0xF) as usize]]
+}
+fn decode_hex_digit(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+pub fn decode_hex_byte(digits: [u8; 2]) -> Option<u8> {
+    Some(decode_hex_digit(digits[0])? << 4 | decode_hex_digit(digits[1])?)
+}
+/// Big endian number, like addresses and lengths in packets
+pub fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |value, digit| {
+        Some(value << 4 | decode_hex_digit(*digit)? as u64)
+    })
+}
+/// Little endian value, like registers in `G` and `P` packets
+pub fn parse_hex_le(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 || !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits.chunks(2).rev().try_fold(0u64, |value, byte| {
+        Some(value << 8 | decode_hex_byte([byte[0], byte[1]])? as u64)
+    })
+}
+
+/// Builds a reply packet, everything that doesn't fit is cut off
+pub struct Reply {
+    pub data: Packet,
+}
+impl Reply {
+    pub fn new() -> Self {
+        Reply {
+            data: Packet::new(),
+        }
+    }
+    pub fn push_str(&mut self, text: &str) {
+        for byte in text.bytes() {
+            let _ = self.data.push(byte);
+        }
+    }
+    pub fn push_hex_byte(&mut self, byte: u8) {
+        for digit in encode_hex_byte(byte) {
+            let _ = self.data.push(digit);
+        }
+    }
+    /// Registers are sent as little endian bytes
+    pub fn push_hex_le(&mut self, value: u64, size: usize) {
+        for byte in &value.to_le_bytes()[..size] {
+            self.push_hex_byte(*byte);
+        }
+    }
+    /// Numbers outside of register data are big endian without leading zeroes
+    pub fn push_hex_number(&mut self, value: u64) {
+        let digits = (64 - value.leading_zeros()).div_ceil(4).max(1);
+        for shift in (0..digits).rev() {
+            let _ = self.data.push(HEX[((value >> (shift * 4)) & 0xF) as usize]);
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering},
+};
+
+use super::{
+    COM2, FRAMES, Resume, StopReason, frame, memory,
+    packet::{
+        Connection, MAX_PACKET_SIZE, Packet, Reply, decode_hex_byte, parse_hex, parse_hex_le,
+    },
+};
+use crate::{interrupts::trap::TrapFrame, percpu};
+
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xCC;
+// bytes of memory in one `m`/`M` packet, each takes 2 hex digits
+const MAX_MEMORY_ACCESS: usize = (MAX_PACKET_SIZE - 32) / 2;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// addresses of breakpoints set by gdb (0 -> free slot), read without locks by every cpu
+static BREAKPOINTS: [AtomicU64; MAX_BREAKPOINTS] = [const { AtomicU64::new(0) }; MAX_BREAKPOINTS];
+// bytes that int3 replaced, same index as in `BREAKPOINTS`
+static ORIGINAL: [AtomicU8; MAX_BREAKPOINTS] = [const { AtomicU8::new(0) }; MAX_BREAKPOINTS];
+// gdb is waiting for a stop reply after `c`/`s`
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+fn find_breakpoint(address: u64) -> Option<usize> {
+    if address == 0 {
+        return None;
+    }
+    BREAKPOINTS
+        .iter()
+        .position(|breakpoint| breakpoint.load(Ordering::Acquire) == address)
+}
+
+pub(super) fn is_breakpoint(address: u64) -> bool {
+    find_breakpoint(address).is_some()
+}
+
+fn insert_breakpoint(address: u64) -> Result<(), ()> {
+    if is_breakpoint(address) {
+        return Ok(());
+    }
+    let slot = find_breakpoint_slot().ok_or(())?;
+    let mut original = [0];
+    memory::read(address, &mut original)?;
+    memory::write(address, &[INT3])?;
+    ORIGINAL[slot].store(original[0], Ordering::Release);
+    BREAKPOINTS[slot].store(address, Ordering::Release);
+    Ok(())
+}
+fn find_breakpoint_slot() -> Option<usize> {
+    BREAKPOINTS
+        .iter()
+        .position(|breakpoint| breakpoint.load(Ordering::Acquire) == 0)
+}
+
+fn remove_breakpoint(address: u64) -> Result<(), ()> {
+    let slot = find_breakpoint(address).ok_or(())?;
+    memory::write(address, &[ORIGINAL[slot].load(Ordering::Acquire)])?;
+    BREAKPOINTS[slot].store(0, Ordering::Release);
+    Ok(())
+}
+fn remove_all_breakpoints() {
+    for breakpoint in &BREAKPOINTS {
+        let _ = remove_breakpoint(breakpoint.load(Ordering::Acquire));
+    }
+}
+
+/// Puts the original byte back, but keeps the breakpoint, so a cpu can step over it
+pub(super) fn remove_breakpoint_temporarily(address: u64) {
+    if let Some(slot) = find_breakpoint(address) {
+        let _ = memory::write(address, &[ORIGINAL[slot].load(Ordering::Acquire)]);
+    }
+}
+/// Undoes `remove_breakpoint_temporarily`, unless gdb removed the breakpoint in the meantime
+pub(super) fn reinsert_breakpoint(address: u64) {
+    if is_breakpoint(address) {
+        let _ = memory::write(address, &[INT3]);
+    }
+}
+
+/// Talks to gdb until it resumes the kernel. All other cpus are parked.
+pub(super) fn run(cpu: usize, reason: StopReason, packet_started: bool) -> Resume {
+    let connection = Connection::new(COM2);
+    let mut stub = Stub {
+        cpu,
+        reason,
+        selected: cpu,
+    };
+
+    // gdb doesn't know yet that the kernel stopped
+    if ATTACHED.load(Ordering::Acquire) && !packet_started {
+        let mut reply = Reply::new();
+        stub.stop_reply(&mut reply);
+        connection.send_packet(&reply.data);
+    }
+
+    let mut packet = Packet::new();
+    let mut started = packet_started;
+    loop {
+        connection.read_packet(&mut packet, started);
+        started = false;
+        ATTACHED.store(true, Ordering::Release);
+
+        let mut reply = Reply::new();
+        match stub.handle(&packet, &mut reply) {
+            Some(resume) => {
+                // `D` is acknowledged, `c`, `s` and `k` are answered by the next stop reply
+                if !reply.data.is_empty() {
+                    connection.send_packet(&reply.data);
+                }
+                return resume;
+            }
+            None => connection.send_packet(&reply.data),
+        }
+    }
+}
+
+struct Stub {
+    // cpu that trapped
+    cpu: usize,
+    reason: StopReason,
+    // thread (cpu) selected with `Hg`, registers are read from it
+    selected: usize,
+}
+
+impl Stub {
+    /// Returns `Some` when the kernel should resume, the reply is empty for unsupported packets
+    fn handle(&mut self, packet: &[u8], reply: &mut Reply) -> Option<Resume> {
+        let (&command, arguments) = packet.split_first()?;
+        let result = match command {
+            b'?' => {
+                self.stop_reply(reply);
+                Ok(())
+            }
+            b'g' => self.read_registers(reply),
+            b'G' => self.write_registers(arguments),
+            b'p' => self.read_register(arguments, reply),
+            b'P' => self.write_register(arguments),
+            b'm' => read_memory(arguments, reply),
+            b'M' => write_memory(arguments),
+            // only software breakpoints are supported, empty reply for the rest
+            b'Z' | b'z' if !arguments.starts_with(b"0,") => return None,
+            b'Z' => change_breakpoint(arguments, true),
+            b'z' => change_breakpoint(arguments, false),
+            b'H' => self.select_thread(arguments),
+            b'T' => match parse_thread(arguments) {
+                Some(cpu) if has_frame(cpu) => Ok(()),
+                _ => Err(()),
+            },
+            b'q' => {
+                self.query(arguments, reply);
+                return None;
+            }
+            b'c' | b's' => {
+                if !arguments.is_empty() {
+                    let Some(address) = parse_hex(arguments) else {
+                        reply.push_str("E01");
+                        return None;
+                    };
+                    self.frame().rip = address;
+                }
+                return Some(match command {
+                    b'c' => Resume::Continue,
+                    _ => Resume::Step,
+                });
+            }
+            b'D' => {
+                remove_all_breakpoints();
+                ATTACHED.store(false, Ordering::Release);
+                reply.push_str("OK");
+                return Some(Resume::Continue);
+            }
+            // the kernel can't be killed, detach instead
+            b'k' => {
+                remove_all_breakpoints();
+                ATTACHED.store(false, Ordering::Release);
+                return Some(Resume::Continue);
+            }
+            _ => return None,
+        };
+        if reply.data.is_empty() {
+            reply.push_str(match result {
+                Ok(()) => "OK",
+                Err(()) => "E01",
+            });
+        }
+        None
+    }
+
+    fn frame(&self) -> &'static mut TrapFrame {
+        self.selected_frame()
+            .expect("the cpu that talks to gdb is always stopped")
+    }
+    fn selected_frame(&self) -> Option<&'static mut TrapFrame> {
+        unsafe { frame(self.selected).or_else(|| frame(self.cpu)) }
+    }
+
+    fn stop_reply(&self, reply: &mut Reply) {
+        reply.push_str("T");
+        reply.push_hex_byte(match self.reason {
+            StopReason::Interrupt => SIGINT,
+            StopReason::Breakpoint | StopReason::Step => SIGTRAP,
+        });
+        reply.push_str("thread:");
+        reply.push_hex_number(thread_id(self.cpu));
+        reply.push_str(";");
+        let stopped_at = unsafe { frame(self.cpu) }.map(|frame| frame.rip);
+        if self.reason == StopReason::Breakpoint && stopped_at.is_some_and(is_breakpoint) {
+            reply.push_str("swbreak:;");
+        }
+    }
+
+    fn read_registers(&self, reply: &mut Reply) -> Result<(), ()> {
+        let frame = self.frame();
+        for number in 0..REGISTER_COUNT {
+            let (value, size) = register(frame, number).ok_or(())?;
+            reply.push_hex_le(value, size);
+        }
+        Ok(())
+    }
+    fn write_registers(&self, mut data: &[u8]) -> Result<(), ()> {
+        let frame = self.frame();
+        for number in 0..REGISTER_COUNT {
+            let (_, size) = register(frame, number).ok_or(())?;
+            if data.len() < size * 2 {
+                // gdb can send fewer registers than it read
+                break;
+            }
+            let (digits, rest) = data.split_at(size * 2);
+            set_register(frame, number, parse_hex_le(digits).ok_or(())?);
+            data = rest;
+        }
+        Ok(())
+    }
+    fn read_register(&self, arguments: &[u8], reply: &mut Reply) -> Result<(), ()> {
+        let number = parse_hex(arguments).ok_or(())? as usize;
+        let (value, size) = register(self.frame(), number).ok_or(())?;
+        reply.push_hex_le(value, size);
+        Ok(())
+    }
+    fn write_register(&self, arguments: &[u8]) -> Result<(), ()> {
+        let (number, value) = split(arguments, b'=').ok_or(())?;
+        let number = parse_hex(number).ok_or(())? as usize;
+        let frame = self.frame();
+        register(frame, number).ok_or(())?;
+        set_register(frame, number, parse_hex_le(value).ok_or(())?);
+        Ok(())
+    }
+
+    fn select_thread(&mut self, arguments: &[u8]) -> Result<(), ()> {
+        let (&operation, thread) = arguments.split_first().ok_or(())?;
+        // `Hc` selects what `c`/`s` resume, every cpu resumes together anyway
+        if operation == b'c' {
+            return Ok(());
+        }
+        self.selected = match thread {
+            // any or all threads
+            b"0" | b"-1" => self.cpu,
+            thread => parse_thread(thread)
+                .filter(|cpu| has_frame(*cpu))
+                .ok_or(())?,
+        };
+        Ok(())
+    }
+
+    fn query(&self, query: &[u8], reply: &mut Reply) {
+        let (name, arguments) = match split(query, b':').or_else(|| split(query, b',')) {
+            Some((name, arguments)) => (name, arguments),
+            None => (query, &[][..]),
+        };
+        match name {
+            b"Supported" => {
+                reply.push_str("PacketSize=");
+                reply.push_hex_number(MAX_PACKET_SIZE as u64);
+                reply.push_str(";swbreak+");
+            }
+            b"Attached" => reply.push_str("1"),
+            b"C" => {
+                reply.push_str("QC");
+                reply.push_hex_number(thread_id(self.selected));
+            }
+            b"fThreadInfo" => {
+                reply.push_str("m");
+                let mut first = true;
+                for cpu in (0..FRAMES.len()).filter(|cpu| has_frame(*cpu)) {
+                    if !first {
+                        reply.push_str(",");
+                    }
+                    first = false;
+                    reply.push_hex_number(thread_id(cpu));
+                }
+            }
+            b"sThreadInfo" => reply.push_str("l"),
+            b"ThreadExtraInfo" => {
+                let Some(cpu) = parse_thread(arguments) else {
+                    reply.push_str("E01");
+                    return;
+                };
+                let mut info = heapless::String::<64>::new();
+                let _ = write!(info, "cpu {cpu}");
+                match percpu::cpu(cpu).and_then(|cpu| cpu.current_task()) {
+                    Some(task) => {
+                        let _ = write!(info, ", task {}", task.as_u64());
+                    }
+                    None => {
+                        let _ = write!(info, ", idle");
+                    }
+                }
+                for byte in info.bytes() {
+                    reply.push_hex_byte(byte);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn has_frame(cpu: usize) -> bool {
+    unsafe { frame(cpu) }.is_some()
+}
+
+/// gdb treats thread 0 as "any", so threads are numbered from 1
+fn thread_id(cpu: usize) -> u64 {
+    cpu as u64 + 1
+}
+fn parse_thread(digits: &[u8]) -> Option<usize> {
+    (parse_hex(digits)? as usize).checked_sub(1)
+}
+
+fn split(data: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let position = data.iter().position(|byte| *byte == separator)?;
+    Some((&data[..position], &data[position + 1..]))
+}
+
+/// `address,length`
+fn parse_range(arguments: &[u8]) -> Option<(u64, usize)> {
+    let (address, length) = split(arguments, b',')?;
+    let length = parse_hex(length)? as usize;
+    (length <= MAX_MEMORY_ACCESS).then_some((parse_hex(address)?, length))
+}
+
+fn read_memory(arguments: &[u8], reply: &mut Reply) -> Result<(), ()> {
+    let (address, length) = parse_range(arguments).ok_or(())?;
+    let mut buffer = [0u8; MAX_MEMORY_ACCESS];
+    let buffer = &mut buffer[..length];
+    memory::read(address, buffer)?;
+    // gdb expects to see the original code under its breakpoints
+    for (slot, breakpoint) in BREAKPOINTS.iter().enumerate() {
+        let offset = breakpoint.load(Ordering::Acquire).wrapping_sub(address);
+        if let Some(byte) = buffer.get_mut(offset as usize) {
+            *byte = ORIGINAL[slot].load(Ordering::Acquire);
+        }
+    }
+    for byte in buffer.iter() {
+        reply.push_hex_byte(*byte);
+    }
+    Ok(())
+}
+
+fn write_memory(arguments: &[u8]) -> Result<(), ()> {
+    let (range, data) = split(arguments, b':').ok_or(())?;
+    let (address, length) = parse_range(range).ok_or(())?;
+    if data.len() != length * 2 {
+        return Err(());
+    }
+    let mut buffer = [0u8; MAX_MEMORY_ACCESS];
+    let buffer = &mut buffer[..length];
+    for (byte, digits) in buffer.iter_mut().zip(data.chunks(2)) {
+        *byte = decode_hex_byte([digits[0], digits[1]]).ok_or(())?;
+    }
+    // breakpoints stay, what's written goes under them
+    for (slot, breakpoint) in BREAKPOINTS.iter().enumerate() {
+        let offset = breakpoint.load(Ordering::Acquire).wrapping_sub(address);
+        if let Some(byte) = buffer.get_mut(offset as usize) {
+            ORIGINAL[slot].store(*byte, Ordering::Release);
+            *byte = INT3;
+        }
+    }
+    memory::write(address, buffer)
+}
+
+/// `0,address,kind`
+fn change_breakpoint(arguments: &[u8], insert: bool) -> Result<(), ()> {
+    let (_, rest) = split(arguments, b',').ok_or(())?;
+    let (address, _) = split(rest, b',').ok_or(())?;
+    let address = parse_hex(address).ok_or(())?;
+    if insert {
+        insert_breakpoint(address)
+    } else {
+        remove_breakpoint(address)
+    }
+}
+
+/// rax..r15, rip, eflags, cs, ss, ds, es, fs, gs, same order as gdb's amd64 target
+const REGISTER_COUNT: usize = 24;
+
+/// Value and size in bytes of a register with gdb's number
+fn register(frame: &TrapFrame, number: usize) -> Option<(u64, usize)> {
+    let value = match number {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        16 => frame.rip,
+        17 => return Some((frame.rflags, 4)),
+        18 => return Some((frame.cs, 4)),
+        19 => return Some((frame.ss, 4)),
+        // data segments aren't used in long mode
+        20..=23 => return Some((0, 4)),
+        _ => return None,
+    };
+    Some((value, 8))
+}
+
+/// Segment registers can't be changed
+fn set_register(frame: &mut TrapFrame, number: usize, value: u64) {
+    let register = match number {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        _ => return,
+    };
+    *register = value;
+}
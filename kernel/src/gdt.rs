@@ -1,45 +1,50 @@
+use alloc::{boxed::Box, vec};
 use lazy_static::lazy_static;
-use x86_64::registers::segmentation::{self, CS, DS, ES, SS};
-use x86_64::{PhysAddr, VirtAddr};
+use x86_64::VirtAddr;
+use x86_64::registers::segmentation::{DS, ES, SS};
 // pub(crate) ::{DS, ES, SS};
 use x86_64::structures::tss::TaskStateSegment;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+fn new_tss(double_fault_stack_end: VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_end;
+    tss
+}
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
 
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE as u64;
-            stack_end
-        };
-        tss
+        let stack_start = VirtAddr::from_ptr(&raw const STACK);
+        let stack_end = stack_start + IST_STACK_SIZE as u64;
+        new_tss(stack_end)
     };
 }
 
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.append(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.append(Descriptor::tss_segment(tss));
 
-        let data_selector = gdt.append(Descriptor::kernel_data_segment());
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-                data_selector,
-            },
-        )
-    };
+    let data_selector = gdt.append(Descriptor::kernel_data_segment());
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            tss_selector,
+            data_selector,
+        },
+    )
+}
+
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(&TSS);
 }
 
 struct Selectors {
@@ -48,18 +53,22 @@ struct Selectors {
     data_selector: SegmentSelector,
 }
 
-pub fn init() -> (u64, usize) {
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::segmentation::{CS, Segment};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        DS::set_reg(GDT.1.data_selector);
-        ES::set_reg(GDT.1.data_selector);
-        SS::set_reg(GDT.1.data_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(gdt.1.code_selector);
+        DS::set_reg(gdt.1.data_selector);
+        ES::set_reg(gdt.1.data_selector);
+        SS::set_reg(gdt.1.data_selector);
+        load_tss(gdt.1.tss_selector);
     }
+}
+
+pub fn init() -> (u64, usize) {
+    load(&GDT);
 
     let gdt = &GDT.0;
     let ptr = gdt as *const _ as *const u64;
@@ -67,3 +76,14 @@ pub fn init() -> (u64, usize) {
     let size = core::mem::size_of_val(gdt);
     (base_phys_address, size)
 }
+
+/// Every AP needs its own TSS (it gets marked busy when loaded) with its own IST stacks,
+/// so they are allocated on heap and leaked, the core uses them until shutdown.
+pub fn init_ap() {
+    let stack = Box::leak(vec![0u8; IST_STACK_SIZE].into_boxed_slice());
+    let stack_start = VirtAddr::from_ptr(stack.as_ptr());
+    let tss = Box::leak(Box::new(new_tss(stack_start + IST_STACK_SIZE as u64)));
+
+    let gdt = Box::leak(Box::new(new_gdt(tss)));
+    load(gdt);
+}
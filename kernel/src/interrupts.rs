@@ -4,10 +4,12 @@ use conquer_once::noblock::OnceCell;
 use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
 
 pub mod apic;
+pub mod ipi;
 pub mod pic;
+pub mod trap;
 
 pub static TSC_HZ: OnceCell<u64> = OnceCell::uninit();
-pub fn init(rsdp: usize) -> u8 {
+pub fn init(rsdp: usize) -> apic::ProcessorsInfo {
     let tsc_ticks_per_ms = pic::calibrate_tsc();
     let tsc_freq_hz = (tsc_ticks_per_ms as f32 * 1000.0 / 1.6944444444) as u64;
     TSC_HZ.try_init_once(|| tsc_freq_hz);
@@ -1,6 +1,7 @@
 use core::{ptr::NonNull, sync::atomic::AtomicU64};
 
-use acpi::{AcpiTable, PhysicalMapping, PlatformInfo};
+use acpi::{AcpiTable, PhysicalMapping, PlatformInfo, platform::ProcessorState};
+use alloc::vec::Vec;
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
 use log::{debug, *};
@@ -72,9 +73,21 @@ pub fn setup_xapic_timer() {
     write_lapic(x86::apic::xapic::XAPIC_TIMER_INIT_COUNT as u64, init_count);
 }
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _guard = InterruptGuard::enter();
     // log::debug!("timer!");
-    crate::time::on_1ms_timer_interrupt();
+    // every core has its own timer, but time is only counted by bootstrap processor
+    if percpu::this_cpu().index == 0 {
+        crate::time::on_1ms_timer_interrupt();
+    }
     xapic().eoi();
+    // can switch to another thread, so EOI has to be sent before
+    crate::thread::scheduler::on_timer_tick();
+}
+
+/// xAPIC id of the core that calls this
+pub fn current_apic_id() -> u32 {
+    // id lives in bits 24..32 of the id register
+    xapic().id() >> 24
 }
 
 // WARN: THIS MIGHT NOT BE TRUE!
@@ -85,20 +98,41 @@ const IRQ_BASE: u8 = 32;
 
 const TIMER_IRQ: u8 = 0; // maps to vector 32
 const KEYBOARD_IRQ: u8 = 1; // maps to vector 33
+const COM2_IRQ: u8 = 3; // maps to vector 35
+const COM1_IRQ: u8 = 4; // maps to vector 36
 
 use lazy_static::*;
 use x86_64::structures::idt::InterruptStackFrame;
 
+use super::trap::{TrapFrame, trap_entry};
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // these take a `TrapFrame`, so gdb can read and change every register
+        unsafe {
+            idt.breakpoint
+                .set_handler_addr(VirtAddr::from_ptr(breakpoint_entry as *const ()));
+            idt.debug
+                .set_handler_addr(VirtAddr::from_ptr(debug_entry as *const ()));
+            idt.non_maskable_interrupt
+                .set_handler_addr(VirtAddr::from_ptr(nmi_entry as *const ()));
+            idt[COM2_IRQ + IRQ_BASE]
+                .set_handler_addr(VirtAddr::from_ptr(com2_entry as *const ()));
+        }
 
         idt[TIMER_IRQ + IRQ_BASE].set_handler_fn(timer_interrupt_handler);
         idt[KEYBOARD_IRQ + IRQ_BASE].set_handler_fn(keyboard_interrupt_handler);
+        idt[COM1_IRQ + IRQ_BASE].set_handler_fn(com1_interrupt_handler);
+        idt[ipi::WAKEUP_VECTOR].set_handler_fn(ipi::wakeup_interrupt_handler);
+        idt[ipi::CALL_VECTOR].set_handler_fn(ipi::call_interrupt_handler);
 
         idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX)
+        };
         idt
     };
 }
@@ -149,18 +183,23 @@ impl acpi::AcpiHandler for AcpiHandler {
 
 pub const PIC_1_OFFSET: u8 = 32;
 
-pub fn init(rsdp: usize) -> u8 {
+pub fn init(rsdp: usize) -> ProcessorsInfo {
     init_xapic();
 
     map_memory_for_io_apic();
 
     IDT.load();
+    crate::gdb::cpu_ready();
 
     debug!("IDT initialized!");
     setup_xapic_timer();
 
     let mut io_apic = io_apic();
     io_apic.enable(KEYBOARD_IRQ, 0);
+    io_apic.enable(COM1_IRQ, 0);
+    if crate::gdb::is_enabled() {
+        io_apic.enable(COM2_IRQ, 0);
+    }
 
     x86_64::instructions::interrupts::enable();
 
@@ -180,7 +219,27 @@ pub fn init(rsdp: usize) -> u8 {
     init_acpi(rsdp)
 }
 
-pub(crate) fn init_acpi(rsdp: usize) -> u8 {
+/// APIC ids of the processors listed in MADT
+pub struct ProcessorsInfo {
+    pub bsp_apic_id: u32,
+    // in the order they should be brought up, disabled ones are skipped
+    pub ap_apic_ids: Vec<u32>,
+}
+
+/// Per core part of interrupt initialization, has to be called by every AP after its GDT is loaded.
+/// IDT is shared, but local APIC and its timer are per core.
+pub fn init_ap() {
+    IDT.load();
+    crate::gdb::cpu_ready();
+
+    let mut xapic = xapic();
+    xapic.attach();
+    setup_xapic_timer();
+
+    x86_64::instructions::interrupts::enable();
+}
+
+pub(crate) fn init_acpi(rsdp: usize) -> ProcessorsInfo {
     debug!("acpi init",);
     let acpi = unsafe {
         acpi::AcpiTables::from_rsdp(AcpiHandler {}, rsdp).expect("reading acpi did not succed!")
@@ -194,13 +253,21 @@ pub(crate) fn init_acpi(rsdp: usize) -> u8 {
     let processors = processor_info.application_processors;
 
     debug!("boot processor: {:?}", processor_info.boot_processor);
-    let cpu_count = processors.len() as u8;
     for proc in processors.iter() {
         debug!("processor : {proc:?}");
     }
     debug!("acpi: {:?}", acpi.platform_info());
 
-    cpu_count
+    let ap_apic_ids = processors
+        .iter()
+        .filter(|proc| proc.state != ProcessorState::Disabled)
+        .map(|proc| proc.local_apic_id)
+        .collect();
+
+    ProcessorsInfo {
+        bsp_apic_id: processor_info.boot_processor.local_apic_id,
+        ap_apic_ids,
+    }
 }
 
 fn map_memory_for_io_apic() {
@@ -223,8 +290,10 @@ fn map_memory_for_io_apic() {
 use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
 
 use crate::{
-    cpuid, hlt_loop, interrupts,
+    cpuid, gdt, hlt_loop,
+    interrupts::ipi,
     memory::{ACPI_MEMORY_SIZE, ACPI_START_ADDRESS, MAPPER, StaticFrameAllocator},
+    percpu::{self, InterruptGuard},
     time,
 };
 extern "x86-interrupt" fn page_fault_handler(
@@ -242,6 +311,7 @@ extern "x86-interrupt" fn page_fault_handler(
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
+    let _guard = InterruptGuard::enter();
 
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
@@ -249,6 +319,11 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
 
     xapic().eoi();
 }
+extern "x86-interrupt" fn com1_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _guard = InterruptGuard::enter();
+    crate::serial::on_interrupt();
+    xapic().eoi();
+}
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
@@ -257,6 +332,31 @@ extern "x86-interrupt" fn double_fault_handler(
     loop {}
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    log::error!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+trap_entry!(breakpoint_entry, breakpoint_handler);
+extern "C" fn breakpoint_handler(frame: &mut TrapFrame) {
+    if !crate::gdb::on_breakpoint(frame) {
+        log::error!("EXCEPTION: BREAKPOINT\n{:#?}", frame);
+    }
+}
+
+trap_entry!(debug_entry, debug_handler);
+extern "C" fn debug_handler(frame: &mut TrapFrame) {
+    if !crate::gdb::on_debug(frame) {
+        log::error!("EXCEPTION: DEBUG\n{:#?}", frame);
+    }
+}
+
+trap_entry!(nmi_entry, nmi_handler);
+extern "C" fn nmi_handler(frame: &mut TrapFrame) {
+    if !crate::gdb::on_nmi(frame) {
+        log::warn!("unexpected NMI\n{:#?}", frame);
+    }
+}
+
+trap_entry!(com2_entry, com2_interrupt_handler);
+extern "C" fn com2_interrupt_handler(frame: &mut TrapFrame) {
+    let _guard = InterruptGuard::enter();
+    // before stopping, the kernel can stay stopped for a long time
+    xapic().eoi();
+    crate::gdb::on_interrupt(frame);
 }
@@ -0,0 +1,156 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86::apic::{
+    ApicControl, ApicId, DeliveryMode, DeliveryStatus, DestinationMode, DestinationShorthand, Icr,
+    Level, TriggerMode,
+};
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::{
+    interrupts::apic::xapic,
+    percpu::{self, InterruptGuard},
+};
+
+/// IPI used to get idle cpu out of `hlt` so it checks its run queue
+pub const WAKEUP_VECTOR: u8 = 0x40;
+/// IPI that makes cpu run functions from its call queue
+pub const CALL_VECTOR: u8 = 0x41;
+
+/// how many cross cpu calls can wait for a single cpu at once
+pub const CALL_QUEUE_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub enum IpiTarget {
+    /// index of the cpu (same as `PerCpu::index`)
+    Cpu(usize),
+    All,
+    AllButSelf,
+}
+
+/// Sends fixed IPI with `vector` to `target`.
+/// WARN: broadcasts also reach cpus that didn't load IDT yet, so only use them when all cpus are online
+pub fn send_ipi(target: IpiTarget, vector: u8) {
+    send(target, vector, DeliveryMode::Fixed);
+}
+
+/// Non maskable interrupt, reaches the target even when it runs with interrupts disabled
+pub fn send_nmi(target: IpiTarget) {
+    // vector is ignored for NMIs
+    send(target, 0, DeliveryMode::NMI);
+}
+
+fn send(target: IpiTarget, vector: u8, delivery_mode: DeliveryMode) {
+    let (destination, shorthand) = match target {
+        IpiTarget::Cpu(index) => {
+            let cpu = percpu::cpu(index).expect("IPI target cpu is not online");
+            (cpu.apic_id, DestinationShorthand::NoShorthand)
+        }
+        IpiTarget::All => (0, DestinationShorthand::AllIncludingSelf),
+        IpiTarget::AllButSelf => (0, DestinationShorthand::AllExcludingSelf),
+    };
+    let icr = Icr::for_xapic(
+        vector,
+        ApicId::XApic(destination as u8),
+        shorthand,
+        delivery_mode,
+        DestinationMode::Physical,
+        DeliveryStatus::Idle,
+        Level::Assert,
+        TriggerMode::Edge,
+    );
+    // ICR is written in 2 parts, interrupt handler sending its own IPI in between would mix them up
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe { xapic().send_ipi(icr) });
+}
+
+pub(crate) extern "x86-interrupt" fn wakeup_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _guard = InterruptGuard::enter();
+    // nothing to do, returning from the interrupt is enough to leave `hlt`
+    xapic().eoi();
+}
+
+/// Lives on the stack of the caller, which waits until every target ran `func`
+struct CallRequest<'a> {
+    func: &'a (dyn Fn() + Sync),
+    pending: AtomicUsize,
+}
+
+/// Pointer to `CallRequest` that is put on the call queue of the target cpu
+pub(crate) struct CallPtr(*const CallRequest<'static>);
+unsafe impl Send for CallPtr {}
+
+/// Runs `func` on cpu with `index` and waits until it's done.
+/// Panics if that cpu is not online, same as sending it an IPI.
+pub fn run_on_cpu(index: usize, func: impl Fn() + Sync) {
+    run_on(IpiTarget::Cpu(index), func);
+}
+
+/// Runs `func` on every online cpu (including this one) and waits until all of them are done.
+pub fn run_on_all_cpus(func: impl Fn() + Sync) {
+    run_on(IpiTarget::All, func);
+}
+
+/// Runs `func` on every online cpu except this one and waits until all of them are done.
+pub fn run_on_other_cpus(func: impl Fn() + Sync) {
+    run_on(IpiTarget::AllButSelf, func);
+}
+
+fn run_on(target: IpiTarget, func: impl Fn() + Sync) {
+    let this_cpu = percpu::this_cpu();
+    if let IpiTarget::Cpu(index) = target {
+        // otherwise the loop below would skip it and return as if `func` ran
+        assert!(percpu::cpu(index).is_some(), "call target cpu is not online");
+    }
+    let is_target = |index: usize| match target {
+        IpiTarget::Cpu(target) => target == index,
+        IpiTarget::All => true,
+        IpiTarget::AllButSelf => index != this_cpu.index,
+    };
+
+    let request = CallRequest {
+        func: &func,
+        pending: AtomicUsize::new(0),
+    };
+    // SAFETY: the call queues need `'static`, but this function doesn't return before `pending`
+    // drops to 0, so no cpu can use the request once `func` and `request` go out of scope
+    let request_ptr = core::ptr::from_ref(&request).cast::<CallRequest<'static>>();
+
+    // targets are sent IPIs one by one instead of broadcast, so cpus that aren't online are skipped
+    for cpu in percpu::all_cpus() {
+        if cpu.index == this_cpu.index || !is_target(cpu.index) {
+            continue;
+        }
+        request.pending.fetch_add(1, Ordering::AcqRel);
+        while cpu.call_queue.push(CallPtr(request_ptr)).is_err() {
+            // target is busy, it might be waiting on our call queue
+            run_pending_calls();
+            core::hint::spin_loop();
+        }
+        send_ipi(IpiTarget::Cpu(cpu.index), CALL_VECTOR);
+    }
+
+    if is_target(this_cpu.index) {
+        func();
+    }
+
+    while request.pending.load(Ordering::Acquire) != 0 {
+        // other cpu might be waiting for us to run its call, so keep serving ours to avoid deadlock
+        run_pending_calls();
+        core::hint::spin_loop();
+    }
+}
+
+/// Runs functions that other cpus asked this one to run
+fn run_pending_calls() {
+    while let Some(CallPtr(request)) = percpu::this_cpu().call_queue.pop() {
+        let request = unsafe { &*request };
+        (request.func)();
+        // WARN: request can be gone right after this
+        request.pending.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+pub(crate) extern "x86-interrupt" fn call_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _guard = InterruptGuard::enter();
+    run_pending_calls();
+    xapic().eoi();
+}
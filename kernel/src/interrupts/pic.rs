@@ -34,8 +34,8 @@ use pic8259::ChainedPics;
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: IrqSafeMutex<ChainedPics> =
+    IrqSafeMutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 pub fn disable_pic() {
     unsafe { PICS.lock().disable() };
@@ -115,7 +115,7 @@ use x86_64::{
     structures::idt::{InterruptDescriptorTable, PageFaultErrorCode},
 };
 
-use crate::hlt_loop;
+use crate::{hlt_loop, sync::IrqSafeMutex};
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
     unsafe {
         PICS.lock()
@@ -0,0 +1,80 @@
+/// Every general purpose register of the interrupted code, pushed by `trap_entry!` on top of
+/// what the cpu pushes itself. Changes to it are restored when the handler returns.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    // pushed by the cpu
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Trap flag, the cpu raises a debug exception after the next instruction
+pub const RFLAGS_TRAP: u64 = 1 << 8;
+
+/// Defines a naked interrupt entry that saves all registers into a `TrapFrame` and calls
+/// `$handler: extern "C" fn(&mut TrapFrame)`. Only for vectors without an error code.
+/// The entry is put into IDT with `set_handler_addr`.
+macro_rules! trap_entry {
+    ($name:ident, $handler:path) => {
+        #[unsafe(naked)]
+        pub(crate) extern "C" fn $name() {
+            // the cpu aligned the stack before pushing its 5 values, so after 15 more it's
+            // 16 byte aligned again for the call
+            core::arch::naked_asm!(
+                "push rax",
+                "push rbx",
+                "push rcx",
+                "push rdx",
+                "push rsi",
+                "push rdi",
+                "push rbp",
+                "push r8",
+                "push r9",
+                "push r10",
+                "push r11",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+                "mov rdi, rsp",
+                "call {handler}",
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+                "pop r8",
+                "pop rbp",
+                "pop rdi",
+                "pop rsi",
+                "pop rdx",
+                "pop rcx",
+                "pop rbx",
+                "pop rax",
+                "iretq",
+                handler = sym $handler,
+            )
+        }
+    };
+}
+pub(crate) use trap_entry;
@@ -2,6 +2,7 @@
 #![no_std]
 #![no_main]
 #![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
@@ -16,11 +17,13 @@ use conquer_once::spin::OnceCell;
 use log::debug;
 use spin::mutex::Mutex;
 
-use crate::task::{StaticTask, keyboard::ScancodeStream};
+use crate::task::{TaskPriority, keyboard::ScancodeStream};
 
 // add a `config` argument to the `entry_point` macro call
 pub mod allocator;
+pub mod boot_config;
 pub mod framebuffer;
+pub mod gdb;
 pub mod gdt;
 
 pub mod graphics;
@@ -28,18 +31,39 @@ pub mod interrupts;
 pub mod logger;
 pub mod memory;
 pub mod panic;
+pub mod percpu;
 pub mod qemu;
 pub mod serial;
+pub mod sync;
 pub mod task;
+pub mod testing;
+pub mod thread;
 pub mod threads;
 pub mod time;
 
+/// Used by `entry_point` and the test kernels
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+#[cfg(test)]
+entry_point!(test_kernel_main, config = &BOOTLOADER_CONFIG);
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    testing::run(boot_info, test_main);
+}
+
 pub fn cpuid() {
     debug!("cpuid {:?}", x86::cpuid::CpuId::new());
 }
 
 pub fn init_kernel(boot_info: &'static mut bootloader_api::BootInfo) {
-    logger::init_logger(log::LevelFilter::Debug);
+    boot_config::init(boot_info);
+    logger::init_logger();
+    boot_config::log_summary();
     let physical_memory_offset = boot_info
         .physical_memory_offset
         .as_ref()
@@ -53,6 +77,13 @@ pub fn init_kernel(boot_info: &'static mut bootloader_api::BootInfo) {
 
     allocator::init_heap().expect("heap initialization failed");
 
+    // local APIC isn't mapped yet, so take apic id from cpuid
+    let bsp_apic_id = x86::cpuid::CpuId::new()
+        .get_feature_info()
+        .expect("cpuid feature info is not available")
+        .initial_local_apic_id();
+    percpu::init(0, bsp_apic_id as u32);
+
     let framebuffer = boot_info
         .framebuffer
         .as_mut()
@@ -63,46 +94,63 @@ pub fn init_kernel(boot_info: &'static mut bootloader_api::BootInfo) {
 
     let (gdt_base_phys_address, gdt_size) = gdt::init();
     let rsdp = boot_info.rsdp_addr.take().unwrap();
-    let cpu_count = interrupts::init(rsdp as usize);
+    gdb::init();
+    let processors = interrupts::init(rsdp as usize);
 
     RSDP.get_or_init(|| rsdp);
     threads::init(
-        cpu_count,
+        processors,
         level_4_table_phys_address,
         gdt_base_phys_address,
         gdt_size,
     );
 
-    task::keyboard::ON_KEY_PRESSED_LISTENERS
-        .lock()
-        .push(on_key_debug_other_things);
+    task::keyboard::ON_KEY_PRESSED_LISTENERS.register(on_key_debug_other_things);
 
     debug!("Initialization fished successfully!");
+    gdb::wait_for_debugger();
 }
 
 pub async fn test_debug_every_second() {
+    let mut interval = time::interval(core::time::Duration::from_secs(1));
     loop {
+        interval.tick().await;
         debug!("sec!");
-        time::wait_ms(1000).await;
     }
 }
 static RSDP: OnceCell<u64> = OnceCell::uninit();
 // used for easy triggering of debugs for all sorts of stuff
 pub fn on_key_debug_other_things(_: &pc_keyboard::DecodedKey) {
     // interrupts::apic::init_acpi(*RSDP.get().unwrap() as usize);
-    // debug!(
-    //     "threads entrypoint test: {}",
-    //     threads::ap_entrypoint::test.load(core::sync::atomic::Ordering::Relaxed)
-    // );
+    // debug!("online cpus: {}", threads::online_cpus());
 }
 
 pub fn start_task_executor_loop() -> ! {
     debug!("start_task_executor_loop");
+    thread::init_cpu();
     let mut executor = task::executor::Executor::new();
-    task::executor::TASK_SPAWNER.spawn(StaticTask::new(task::keyboard::print_keypresses()));
-    task::executor::TASK_SPAWNER.spawn(StaticTask::new(logger::handel_log_que()));
-    task::executor::TASK_SPAWNER.spawn(StaticTask::new(time::run_timer_loop()));
-    task::executor::TASK_SPAWNER.spawn(StaticTask::new(test_debug_every_second()));
+    let spawner = &task::executor::TASK_SPAWNER;
+    spawner.spawn_with(
+        "keyboard",
+        TaskPriority::InterruptBottomHalf,
+        task::keyboard::print_keypresses(),
+    );
+    spawner.spawn_with("logger", TaskPriority::Interactive, logger::handel_log_que());
+    spawner.spawn_with(
+        "log drain",
+        TaskPriority::InterruptBottomHalf,
+        logger::run_log_drain(),
+    );
+    spawner.spawn_with(
+        "timer",
+        TaskPriority::InterruptBottomHalf,
+        time::run_timer_loop(),
+    );
+    spawner.spawn_with(
+        "debug every second",
+        TaskPriority::Background,
+        test_debug_every_second(),
+    );
 
     executor.run();
 }
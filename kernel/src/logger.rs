@@ -1,159 +1,235 @@
-use crate::{logger, serial::SerialPort};
-use alloc::vec::Vec;
-use conquer_once::spin::OnceCell;
-use core::fmt::Write;
-use crossbeam_queue::ArrayQueue;
-use log::{LevelFilter, warn};
-use spin::Mutex;
-use spinning_top::Spinlock;
-
-pub struct LockedLogger {
-    serial: Spinlock<SerialPort>,
-}
+mod buffer;
+mod dmesg;
+mod entry;
+mod filter;
+mod record;
 
-static LOGGER: OnceCell<LockedLogger> = OnceCell::uninit();
-
-pub struct LogStream {
-    _private: (),
-}
-impl LogStream {
-    pub fn new() -> Self {
-        LOG_QUE
-            .try_init_once(|| ArrayQueue::new(32))
-            .expect("LogStream::new should only be called once");
-        LogStream { _private: () }
-    }
-}
+use crate::{boot_config, logger, percpu, serial::SerialPort, sync::Listeners};
+use conquer_once::spin::OnceCell;
 use core::{
-    pin::Pin,
-    task::{Context, Poll},
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
 };
-use futures_util::stream::Stream;
-
 use futures_util::task::AtomicWaker;
+use log::Level;
+use x86_64::instructions::interrupts;
 
-static WAKER: AtomicWaker = AtomicWaker::new();
+pub(crate) use buffer::LogBuffer;
+use entry::LogEntry;
+use record::LogLine;
 
-pub const MAX_LOG_SIZE: usize = 80;
-
-impl Stream for LogStream {
-    type Item = [u8; MAX_LOG_SIZE];
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<[u8; MAX_LOG_SIZE]>> {
-        let queue = LOG_QUE.try_get().expect("log queue not initialized");
+pub use dmesg::{DMESG_SIZE, DmesgReader};
+pub use filter::{
+    DEFAULT_LEVEL, FilterError, LogFilter, apply_directives, clear_level, current_filter,
+    set_filter, set_level,
+};
+pub use record::LogRecord;
 
-        // fast path
-        if let Some(log) = queue.pop() {
-            return Poll::Ready(Some(log));
-        }
-        WAKER.register(cx.waker());
-        match queue.pop() {
-            Some(log) => {
-                WAKER.take();
-                Poll::Ready(Some(log))
-            }
-            None => Poll::Pending,
-        }
-    }
+/// Logs go to the per-cpu `LogBuffer` first, holding `serial` means draining them.
+/// Only early boot and logs too long for the buffer wait for it, so an interrupt (or panic) in the
+/// middle of a drain can't deadlock.
+pub struct LockedLogger {
+    serial: spin::Mutex<SerialPort>,
 }
 
+static LOGGER: OnceCell<LockedLogger> = OnceCell::uninit();
+
 use futures_util::stream::StreamExt;
 
 pub(crate) async fn handel_log_que() {
-
-    let mut stream = LogStream::new();
-    while let Some(log) = stream.next().await {
-        for listener in ON_LOG_LISTENERS.lock().iter() {
-            listener(&log);
-        }
+    // from the start, so listeners also get the logs from before the executor started
+    let mut reader = DmesgReader::from_start();
+    while let Some(log) = reader.next().await {
+        ON_LOG_LISTENERS.call_each(|listener| listener(&log));
     }
 }
-pub type OnLogFunction = fn(&[u8; MAX_LOG_SIZE]);
-pub static ON_LOG_LISTENERS: Mutex<Vec<OnLogFunction>> = Mutex::new(Vec::new());
+pub type OnLogFunction = fn(&LogRecord);
+pub static ON_LOG_LISTENERS: Listeners<OnLogFunction> = Listeners::new();
 
 impl LockedLogger {
     pub fn new() -> Self {
         LockedLogger {
-            serial: Spinlock::new(unsafe { SerialPort::init() }),
+            serial: spin::Mutex::new(unsafe { SerialPort::init() }),
         }
     }
 }
 
-pub fn init_logger(log_level: LevelFilter) {
+/// Every target logs at `DEFAULT_LEVEL`,
+/// `log=<directives>` in the boot config can change it per target (see `LogFilter::apply`)
+pub fn init_logger() {
     let logger = logger::LOGGER.get_or_init(LockedLogger::new);
 
     log::set_logger(logger).expect("setting logger did not succeed");
-    log::set_max_level(convert_level(log_level));
+    set_filter(LogFilter::new(DEFAULT_LEVEL));
     log::info!("initialized logs");
-}
-fn convert_level(level: LevelFilter) -> log::LevelFilter {
-    match level {
-        LevelFilter::Off => log::LevelFilter::Off,
-        LevelFilter::Error => log::LevelFilter::Error,
-        LevelFilter::Warn => log::LevelFilter::Warn,
-        LevelFilter::Info => log::LevelFilter::Info,
-        LevelFilter::Debug => log::LevelFilter::Debug,
-        LevelFilter::Trace => log::LevelFilter::Trace,
+
+    if let Some(directives) = boot_config::get().log {
+        match apply_directives(directives) {
+            Ok(()) => log::info!("log filter: {}", current_filter()),
+            Err(err) => log::warn!("invalid log directives {directives:?}: {err:?}"),
+        }
     }
 }
 
-static LOG_QUE: OnceCell<ArrayQueue<[u8; MAX_LOG_SIZE]>> = OnceCell::uninit();
-impl log::Log for LockedLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+impl LockedLogger {
+    /// Writes buffered logs of all cpus to dmesg and serial, oldest first.
+    /// Returns right away if another cpu (or thread) is draining, it gets to these logs too.
+    fn drain(&self) {
+        loop {
+            let Some(mut serial) = self.serial.try_lock() else {
+                // in case it was just done
+                request_drain();
+                return;
+            };
+            drain_buffers(&mut serial, false);
+            drop(serial);
+
+            // logs pushed after the last check whose own drain failed to take the lock
+            if !percpu::all_cpus().any(|cpu| cpu.log_buffer.peek_order().is_some()) {
+                return;
+            }
+        }
     }
+}
 
-    fn log(&self, record: &log::Record) {
-        let mut serial = self.serial.lock();
-        writeln!(serial, "{:5}: {}", record.level(), record.args()).unwrap();
-        if !LOG_QUE.is_initialized() {
-            return;
+fn drain_buffers(serial: &mut SerialPort, panicking: bool) {
+    for cpu in percpu::all_cpus() {
+        let dropped = cpu.log_buffer.take_dropped();
+        if dropped > 0 {
+            let args = format_args!(
+                "log buffer of cpu{} was full, dropped {dropped} logs",
+                cpu.index
+            );
+            let entry = LogEntry::new(Level::Warn, module_path!(), args);
+            write_entry(serial, &entry.line(), panicking);
         }
+    }
+    while let Some(cpu) = percpu::all_cpus()
+        .filter(|cpu| cpu.log_buffer.peek_order().is_some())
+        .min_by_key(|cpu| cpu.log_buffer.peek_order())
+    {
+        // SAFETY: caller holds the serial lock
+        let Some(entry) = (unsafe { cpu.log_buffer.pop() }) else {
+            continue;
+        };
+        write_entry(serial, &entry.line(), panicking);
+    }
+}
 
-        if let Ok(queue) = LOG_QUE.try_get() {
-            let mut buffer = [0u8; MAX_LOG_SIZE];
-            let mut buffer_writer = BufferWriter::new(&mut buffer);
-            match writeln!(buffer_writer, "{:5}: {}", record.level(), record.args()) {
-                Ok(_) => {}
-                Err(e) => {
-                    log::error!("err while writing log to buffer:{e}")
-                }
-            };
+fn write_entry(serial: &mut SerialPort, entry: &LogLine, panicking: bool) {
+    if panicking {
+        dmesg::try_push(entry);
+    } else {
+        dmesg::push(entry);
+    }
+    // serial can't fail, and logging the error would only recurse into here
+    let _ = writeln!(serial, "{entry}");
+}
 
-            if queue.push(buffer).is_err() {
-                writeln!(serial, "WARN: log queue full; dropping log").unwrap();
-            }
+static DRAIN_REQUESTED: AtomicBool = AtomicBool::new(false);
+static DRAIN_WAKER: AtomicWaker = AtomicWaker::new();
+
+// logs from interrupt handlers are only buffered, this gets them written out
+fn request_drain() {
+    DRAIN_REQUESTED.store(true, Ordering::Release);
+    DRAIN_WAKER.wake();
+}
 
-            WAKER.wake();
-        } else {
-            warn!("log queue uninitialized");
+pub(crate) async fn run_log_drain() {
+    loop {
+        futures_util::future::poll_fn(|cx| {
+            if DRAIN_REQUESTED.swap(false, Ordering::AcqRel) {
+                return Poll::Ready(());
+            }
+            DRAIN_WAKER.register(cx.waker());
+            match DRAIN_REQUESTED.swap(false, Ordering::AcqRel) {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        })
+        .await;
+        if let Ok(logger) = LOGGER.try_get() {
+            logger.drain();
         }
     }
+}
 
-    fn flush(&self) {}
+/// Runs `f` with COM1 while no log is written to it
+pub(crate) fn with_serial_port<R>(f: impl FnOnce(&mut SerialPort) -> R) -> R {
+    let logger = LOGGER.try_get().expect("logger was not initialized");
+    f(&mut logger.serial.lock())
 }
 
-struct BufferWriter<'a> {
-    buffer: &'a mut [u8],
-    pos: usize,
+/// Writes to COM1 without logging, eg. output of terminal commands.
+/// Isn't filtered, kept in dmesg or sent to log listeners.
+pub fn print(args: fmt::Arguments) {
+    with_serial_port(|serial| {
+        let _ = serial.write_fmt(args);
+    });
 }
 
-impl<'a> BufferWriter<'a> {
-    fn new(buf: &'a mut [u8]) -> Self {
-        Self {
-            buffer: buf,
-            pos: 0,
+/// For the panic handler: never waits for a lock, when the logs can't be drained right now
+/// the message is written straight to the UART instead
+pub fn panic_log(args: fmt::Arguments) {
+    let entry = LogEntry::new(Level::Error, "panic", args);
+    let serial = LOGGER
+        .try_get()
+        .ok()
+        .and_then(|logger| logger.serial.try_lock());
+    match serial {
+        Some(mut serial) => {
+            // logs from before the panic go first
+            drain_buffers(&mut serial, true);
+            write_entry(&mut serial, &entry.line(), true);
+        }
+        None => {
+            let _ = writeln!(unsafe { SerialPort::emergency() }, "{entry}");
         }
     }
 }
 
-impl<'a> Write for BufferWriter<'a> {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let bytes = s.as_bytes();
-        let available = self.buffer.len().saturating_sub(self.pos);
-        let to_copy = available.min(bytes.len());
-        self.buffer[self.pos..self.pos + to_copy].copy_from_slice(&bytes[..to_copy]);
-        self.pos += to_copy;
-        Ok(())
+impl log::Log for LockedLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        filter::enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let entry = LogEntry::capture(record);
+
+        let Some(cpu) = percpu::try_this_cpu() else {
+            // before per-cpu data exists the cpu runs with interrupts disabled, so nothing can
+            // interrupt this
+            write_entry(&mut self.serial.lock(), &entry.line(), false);
+            return;
+        };
+        // too long for the buffer: formatted on the heap and written right away, after the logs
+        // that are already buffered. Waiting for the drain is fine while the timer can switch
+        // threads, otherwise (interrupt handlers, interrupts disabled) the entry stays cut.
+        if entry.truncated() > 0 && !cpu.in_interrupt() && interrupts::are_enabled() {
+            let message = alloc::format!("{}", record.args());
+            let line = LogLine {
+                target: record.target(),
+                message: &message,
+                ..entry.line()
+            };
+            let mut serial = self.serial.lock();
+            drain_buffers(&mut serial, false);
+            write_entry(&mut serial, &line, false);
+            return;
+        }
+        cpu.log_buffer.push(&entry);
+        if cpu.in_interrupt() {
+            request_drain();
+            return;
+        }
+        self.drain();
+    }
+
+    fn flush(&self) {
+        self.drain();
     }
 }
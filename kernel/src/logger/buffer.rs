@@ -0,0 +1,111 @@
+use alloc::boxed::Box;
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use super::entry::LogEntry;
+
+const SLOT_COUNT: usize = 32;
+
+/// Orders logs from different cpus when they are drained
+static NEXT_ORDER: AtomicU64 = AtomicU64::new(0);
+
+struct Slot {
+    // set once the entry is fully written
+    ready: AtomicBool,
+    order: AtomicU64,
+    entry: UnsafeCell<MaybeUninit<LogEntry>>,
+}
+
+/// Per-cpu queue of logs that were not written to serial yet.
+/// Any number of writers (the cpu itself and interrupt handlers nested in it) and one reader
+/// (whoever holds the drain lock), writing never blocks or allocates.
+pub(crate) struct LogBuffer {
+    slots: Box<[Slot]>,
+    // both only grow, `write - read` is the number of taken slots
+    write: AtomicUsize,
+    read: AtomicUsize,
+    // logs that didn't fit, reported by the next drain
+    dropped: AtomicUsize,
+}
+unsafe impl Sync for LogBuffer {}
+unsafe impl Send for LogBuffer {}
+
+impl LogBuffer {
+    pub fn new() -> Self {
+        LogBuffer {
+            slots: (0..SLOT_COUNT)
+                .map(|_| Slot {
+                    ready: AtomicBool::new(false),
+                    order: AtomicU64::new(0),
+                    entry: UnsafeCell::new(MaybeUninit::uninit()),
+                })
+                .collect(),
+            write: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// `false` when the buffer is full and the entry was dropped
+    pub fn push(&self, entry: &LogEntry) -> bool {
+        let mut write = self.write.load(Ordering::Acquire);
+        loop {
+            if write - self.read.load(Ordering::Acquire) >= self.slots.len() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            match self.write.compare_exchange_weak(
+                write,
+                write + 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(current) => write = current,
+            }
+        }
+
+        // the slot is free and reserved for this writer until `ready` is set
+        let slot = &self.slots[write % self.slots.len()];
+        unsafe { (*slot.entry.get()).write(*entry) };
+        slot.order.store(
+            NEXT_ORDER.fetch_add(1, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        slot.ready.store(true, Ordering::Release);
+        true
+    }
+
+    /// Order of the oldest entry, `None` if it's still being written
+    pub fn peek_order(&self) -> Option<u64> {
+        let read = self.read.load(Ordering::Acquire);
+        if read == self.write.load(Ordering::Acquire) {
+            return None;
+        }
+        let slot = &self.slots[read % self.slots.len()];
+        if !slot.ready.load(Ordering::Acquire) {
+            return None;
+        }
+        Some(slot.order.load(Ordering::Relaxed))
+    }
+
+    /// # Safety
+    /// Only one cpu can read at a time (caller has to hold the drain lock)
+    pub unsafe fn pop(&self) -> Option<LogEntry> {
+        self.peek_order()?;
+        let read = self.read.load(Ordering::Acquire);
+        let slot = &self.slots[read % self.slots.len()];
+        let entry = unsafe { (*slot.entry.get()).assume_init_read() };
+        slot.ready.store(false, Ordering::Relaxed);
+        // frees the slot for writers
+        self.read.store(read + 1, Ordering::Release);
+        Some(entry)
+    }
+
+    pub fn take_dropped(&self) -> usize {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,290 @@
+use alloc::string::String;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_util::stream::Stream;
+use log::Level;
+
+use super::{
+    LogRecord,
+    entry::{MARKER_SIZE, marker, truncate},
+    record::LogLine,
+};
+use crate::{
+    sync::IrqSafeMutex,
+    task::{
+        TaskId,
+        sync::{Notified, Notify},
+    },
+    time::Instant,
+};
+
+/// How many bytes of the newest records are kept, older ones get overwritten
+pub const DMESG_SIZE: usize = 64 * 1024;
+// longer records are cut and end with `…[+N bytes]`, so one record can never push out everything
+// else, and the target length always fits the header
+const MAX_ENTRY_SIZE: usize = DMESG_SIZE / 8;
+const _: () = assert!(MAX_ENTRY_SIZE <= u16::MAX as usize);
+
+// entry layout (little endian):
+// len: u32 (whole entry) | seq: u64 | timestamp_ms: u64 | level: u8 | cpu: u16 | task: u64 |
+// target_len: u16 | target | message
+const HEADER_SIZE: usize = 4 + 8 + 8 + 1 + 2 + 8 + 2;
+const NO_CPU: u16 = u16::MAX;
+const NO_TASK: u64 = u64::MAX;
+
+/// Kept in a static array, so logs from before the heap was initialized are not lost
+static DMESG: IrqSafeMutex<Ring> = IrqSafeMutex::new(Ring::new());
+static NEW_RECORD: Notify = Notify::new();
+
+struct Ring {
+    buffer: [u8; DMESG_SIZE],
+    /// offset and sequence number of the oldest record
+    head: usize,
+    head_seq: u64,
+    /// where the next record starts and the sequence number it gets
+    tail: usize,
+    next_seq: u64,
+    used: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Ring {
+            buffer: [0; DMESG_SIZE],
+            head: 0,
+            head_seq: 0,
+            tail: 0,
+            next_seq: 0,
+            used: 0,
+        }
+    }
+
+    fn copy_in(&mut self, offset: usize, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.buffer[(offset + i) % DMESG_SIZE] = *byte;
+        }
+    }
+    fn copy_out(&self, offset: usize, bytes: &mut [u8]) {
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.buffer[(offset + i) % DMESG_SIZE];
+        }
+    }
+
+    fn entry_len(&self, offset: usize) -> usize {
+        let mut len = [0; 4];
+        self.copy_out(offset, &mut len);
+        u32::from_le_bytes(len) as usize
+    }
+
+    /// Drops the oldest records until `len` more bytes fit
+    fn make_room(&mut self, len: usize) {
+        while self.used + len > DMESG_SIZE {
+            let oldest = self.entry_len(self.head);
+            self.head = (self.head + oldest) % DMESG_SIZE;
+            self.head_seq += 1;
+            self.used -= oldest;
+        }
+    }
+
+    fn push(&mut self, entry: &LogLine) {
+        let (mut target, mut message) = (entry.target, entry.message);
+        let mut cut = None;
+        if HEADER_SIZE + target.len() + message.len() > MAX_ENTRY_SIZE {
+            let keep = MAX_ENTRY_SIZE - HEADER_SIZE - MARKER_SIZE;
+            target = truncate(target, keep);
+            message = truncate(message, keep - target.len());
+            let truncated = entry.target.len() + entry.message.len() - target.len() - message.len();
+            cut = Some(marker(truncated));
+        }
+        let marker = cut.as_deref().unwrap_or_default().as_bytes();
+        let (target, message) = (target.as_bytes(), message.as_bytes());
+        let len = HEADER_SIZE + target.len() + message.len() + marker.len();
+        self.make_room(len);
+
+        let mut header = [0; HEADER_SIZE];
+        header[0..4].copy_from_slice(&(len as u32).to_le_bytes());
+        header[4..12].copy_from_slice(&self.next_seq.to_le_bytes());
+        header[12..20].copy_from_slice(&entry.timestamp.as_ms().to_le_bytes());
+        header[20] = entry.level as u8;
+        let cpu = entry.cpu.map_or(NO_CPU, |cpu| cpu as u16);
+        header[21..23].copy_from_slice(&cpu.to_le_bytes());
+        let task = entry.task.map_or(NO_TASK, TaskId::as_u64);
+        header[23..31].copy_from_slice(&task.to_le_bytes());
+        header[31..33].copy_from_slice(&(target.len() as u16).to_le_bytes());
+
+        let start = self.tail;
+        self.copy_in(start, &header);
+        self.copy_in(start + HEADER_SIZE, target);
+        self.copy_in(start + HEADER_SIZE + target.len(), message);
+        self.copy_in(start + len - marker.len(), marker);
+
+        self.tail = (start + len) % DMESG_SIZE;
+        self.used += len;
+        self.next_seq += 1;
+    }
+
+    fn read(&self, offset: usize) -> (LogRecord, usize) {
+        let mut header = [0; HEADER_SIZE];
+        self.copy_out(offset, &mut header);
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let timestamp = u64::from_le_bytes(header[12..20].try_into().unwrap());
+        let cpu = u16::from_le_bytes(header[21..23].try_into().unwrap());
+        let task = u64::from_le_bytes(header[23..31].try_into().unwrap());
+        let target_len = u16::from_le_bytes(header[31..33].try_into().unwrap()) as usize;
+
+        let mut text = alloc::vec![0; len - HEADER_SIZE];
+        self.copy_out(offset + HEADER_SIZE, &mut text);
+        let (target, message) = text.split_at(target_len);
+
+        let record = LogRecord {
+            level: level_from_u8(header[20]),
+            target: String::from_utf8_lossy(target).into_owned(),
+            timestamp: Instant::from_ms(timestamp),
+            cpu: (cpu != NO_CPU).then_some(cpu as usize),
+            task: (task != NO_TASK).then(|| TaskId::from_u64(task)),
+            message: String::from_utf8_lossy(message).into_owned(),
+        };
+        (record, len)
+    }
+}
+
+fn level_from_u8(level: u8) -> Level {
+    match level {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+/// Saves the entry and wakes every reader that waits for one
+pub(super) fn push(entry: &LogLine) {
+    DMESG.lock().push(entry);
+    NEW_RECORD.notify_waiters();
+}
+/// For the panic handler, which could have interrupted a reader. Readers find out on the next push.
+pub(super) fn try_push(entry: &LogLine) {
+    if let Some(mut ring) = DMESG.try_lock() {
+        ring.push(entry);
+    }
+}
+
+/// Reads records in the order they were logged, every reader has its own position.
+/// Readers that are too slow miss the records that got overwritten, see `lost`.
+pub struct DmesgReader {
+    next_seq: u64,
+    // offset of `next_seq`, only valid while it's still in the ring
+    offset: usize,
+    lost: u64,
+    notified: Option<Notified<'static>>,
+}
+
+impl DmesgReader {
+    /// Starts at the oldest record that is still kept, which is the first log of the boot
+    /// unless more than `DMESG_SIZE` was logged since
+    pub fn from_start() -> Self {
+        let ring = DMESG.lock();
+        DmesgReader::at(ring.head_seq, ring.head)
+    }
+    /// Only reads records logged after it was created
+    pub fn from_now() -> Self {
+        let ring = DMESG.lock();
+        DmesgReader::at(ring.next_seq, ring.tail)
+    }
+    fn at(next_seq: u64, offset: usize) -> Self {
+        DmesgReader {
+            next_seq,
+            offset,
+            lost: 0,
+            notified: None,
+        }
+    }
+
+    /// Sequence number of the record that is read next
+    pub fn position(&self) -> u64 {
+        self.next_seq
+    }
+    /// How many records were overwritten before this reader got to them
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// `None` when there is no new record
+    pub fn read(&mut self) -> Option<LogRecord> {
+        let ring = DMESG.lock();
+        if self.next_seq < ring.head_seq {
+            self.lost += ring.head_seq - self.next_seq;
+            self.next_seq = ring.head_seq;
+            self.offset = ring.head;
+        }
+        if self.next_seq == ring.next_seq {
+            return None;
+        }
+        let (record, len) = ring.read(self.offset);
+        self.offset = (self.offset + len) % DMESG_SIZE;
+        self.next_seq += 1;
+        Some(record)
+    }
+}
+
+/// Never ends, waits for new records instead
+impl Stream for DmesgReader {
+    type Item = LogRecord;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<LogRecord>> {
+        let this = &mut *self;
+        loop {
+            // fast path
+            if let Some(record) = this.read() {
+                this.notified = None;
+                return Poll::Ready(Some(record));
+            }
+            let notified = this.notified.get_or_insert_with(|| NEW_RECORD.notified());
+            if Pin::new(notified).poll(cx).is_ready() {
+                this.notified = None;
+                continue;
+            }
+            // registered now, but a record could have been pushed just before that
+            return match this.read() {
+                Some(record) => {
+                    this.notified = None;
+                    Poll::Ready(Some(record))
+                }
+                None => Poll::Pending,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn record_longer_than_ring_is_cut() {
+        let long = "é".repeat(DMESG_SIZE);
+        let mut reader = DmesgReader::from_now();
+        push(&LogLine {
+            level: Level::Info,
+            timestamp: Instant::now(),
+            cpu: None,
+            task: None,
+            target: "kernel::dmesg_test",
+            message: &long,
+        });
+
+        // other cpus could log at the same time
+        let record = core::iter::from_fn(|| reader.read())
+            .find(|record| record.target == "kernel::dmesg_test")
+            .expect("record wasn't kept");
+        let cut = record.message.split_once('…');
+        let (kept, marker) = cut.expect("record has no marker");
+        let truncated = long.len() - kept.len();
+        assert_eq!(marker, alloc::format!("[+{truncated} bytes]"));
+        assert!(HEADER_SIZE + record.target.len() + record.message.len() <= MAX_ENTRY_SIZE);
+        assert!(kept.chars().all(|char| char == 'é'));
+    }
+}
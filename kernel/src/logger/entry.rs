@@ -0,0 +1,149 @@
+use core::fmt::{self, Write};
+use log::Level;
+
+use super::record::LogLine;
+use crate::{percpu, task::TaskId, time::Instant};
+
+/// Target and message together, longer ones are cut and end with `…[+N bytes]`
+pub(crate) const ENTRY_TEXT_SIZE: usize = 480;
+
+// `…[+N bytes]` with the longest `N`
+pub(super) const MARKER_SIZE: usize = "…[+ bytes]".len() + 20;
+
+/// Log captured into a fixed size buffer, so logging never allocates or takes a lock
+#[derive(Clone, Copy)]
+pub(crate) struct LogEntry {
+    pub level: Level,
+    pub timestamp: Instant,
+    pub cpu: Option<usize>,
+    pub task: Option<TaskId>,
+    target_len: usize,
+    len: usize,
+    // bytes that didn't fit
+    truncated: usize,
+    text: [u8; ENTRY_TEXT_SIZE],
+}
+
+impl LogEntry {
+    pub fn capture(record: &log::Record) -> Self {
+        LogEntry::new(record.level(), record.target(), *record.args())
+    }
+
+    pub fn new(level: Level, target: &str, args: fmt::Arguments) -> Self {
+        let cpu = percpu::try_this_cpu();
+        let mut entry = LogEntry {
+            level,
+            timestamp: Instant::now(),
+            cpu: cpu.map(|cpu| cpu.index),
+            task: cpu.and_then(|cpu| cpu.current_task()),
+            target_len: 0,
+            len: 0,
+            truncated: 0,
+            text: [0; ENTRY_TEXT_SIZE],
+        };
+        let _ = entry.write_str(target);
+        entry.target_len = entry.len;
+        let _ = entry.write_fmt(args);
+        if entry.truncated > 0 {
+            entry.mark_truncated();
+        }
+        entry
+    }
+
+    // makes room for the marker, the bytes it replaces count as truncated too
+    fn mark_truncated(&mut self) {
+        let text = core::str::from_utf8(&self.text[..self.len]).unwrap_or_default();
+        let keep = truncate(text, ENTRY_TEXT_SIZE - MARKER_SIZE).len();
+        self.truncated += self.len - keep;
+        self.len = keep;
+        self.target_len = self.target_len.min(keep);
+
+        let marker = marker(self.truncated);
+        self.text[keep..keep + marker.len()].copy_from_slice(marker.as_bytes());
+        self.len += marker.len();
+    }
+
+    pub fn target(&self) -> &str {
+        core::str::from_utf8(&self.text[..self.target_len]).unwrap_or_default()
+    }
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(&self.text[self.target_len..self.len]).unwrap_or_default()
+    }
+    /// How many bytes of target and message didn't fit, 0 when nothing was cut
+    pub fn truncated(&self) -> usize {
+        self.truncated
+    }
+
+    pub fn line(&self) -> LogLine<'_> {
+        LogLine {
+            level: self.level,
+            timestamp: self.timestamp,
+            cpu: self.cpu,
+            task: self.task,
+            target: self.target(),
+            message: self.message(),
+        }
+    }
+}
+
+/// Only ever writes whole chars, so the text stays valid UTF-8.
+/// Never fails, what doesn't fit is only counted, so the marker can tell how much is missing.
+impl Write for LogEntry {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let text = truncate(s, ENTRY_TEXT_SIZE - self.len);
+        self.text[self.len..self.len + text.len()].copy_from_slice(text.as_bytes());
+        self.len += text.len();
+        self.truncated += s.len() - text.len();
+        Ok(())
+    }
+}
+
+/// `…[+N bytes]`, ends every text that was cut
+pub(super) fn marker(truncated: usize) -> heapless::String<MARKER_SIZE> {
+    let mut marker = heapless::String::new();
+    let _ = write!(marker, "…[+{truncated} bytes]");
+    marker
+}
+
+pub(super) fn truncate(text: &str, max_len: usize) -> &str {
+    if text.len() <= max_len {
+        return text;
+    }
+    let mut end = max_len;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Same format as `LogRecord`
+impl fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.line().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn short_entry_is_kept_whole() {
+        let entry = LogEntry::new(Level::Info, "kernel::test", format_args!("{} {}", 4, 2));
+        assert_eq!(entry.target(), "kernel::test");
+        assert_eq!(entry.message(), "4 2");
+        assert_eq!(entry.truncated(), 0);
+    }
+
+    #[test_case]
+    fn long_entry_ends_with_marker() {
+        let long = "é".repeat(ENTRY_TEXT_SIZE);
+        let entry = LogEntry::new(Level::Info, "kernel::test", format_args!("{long}"));
+        let marker = alloc::format!("…[+{} bytes]", entry.truncated());
+        let kept = entry.message().strip_suffix(marker.as_str());
+        let kept = kept.expect("truncated message has no marker");
+        assert!(entry.target().len() + entry.message().len() <= ENTRY_TEXT_SIZE);
+        assert_eq!(kept.len() + entry.truncated(), long.len());
+        assert!(kept.chars().all(|char| char == 'é'));
+    }
+}
@@ -0,0 +1,257 @@
+use core::{fmt, str::FromStr};
+use log::LevelFilter;
+
+use crate::sync::IrqSafeMutex;
+
+// fixed size, so directives from the boot config can be set before the heap exists
+const MAX_DIRECTIVES: usize = 16;
+const MAX_TARGET_LEN: usize = 64;
+
+/// Level of targets without a directive, until the boot config or `apply` changes it
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterError {
+    UnknownLevel,
+    TooManyDirectives,
+    TargetTooLong,
+}
+
+#[derive(Debug, Clone)]
+struct Directive {
+    target: heapless::String<MAX_TARGET_LEN>,
+    level: LevelFilter,
+}
+
+impl Directive {
+    /// `kernel::memory` matches `kernel::memory` and `kernel::memory::paging`,
+    /// but not `kernel::memory_map`
+    fn matches(&self, target: &str) -> bool {
+        match target.strip_prefix(self.target.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with("::"),
+            None => false,
+        }
+    }
+}
+
+/// Level of every log target: the most specific directive that matches it, or the default level.
+/// Written as `info,kernel::memory=warn,kernel::interrupts=debug`.
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    default: LevelFilter,
+    directives: heapless::Vec<Directive, MAX_DIRECTIVES>,
+}
+
+impl LogFilter {
+    pub const fn new(default: LevelFilter) -> Self {
+        LogFilter {
+            default,
+            directives: heapless::Vec::new(),
+        }
+    }
+
+    pub fn default_level(&self) -> LevelFilter {
+        self.default
+    }
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .filter(|directive| directive.matches(target))
+            .max_by_key(|directive| directive.target.len())
+            .map_or(self.default, |directive| directive.level)
+    }
+    /// The most verbose level of any target, logs above it are filtered out by the log macros
+    pub fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|directive| directive.level)
+            .fold(self.default, Ord::max)
+    }
+
+    pub fn set_default_level(&mut self, level: LevelFilter) {
+        self.default = level;
+    }
+    /// Replaces the level of `target` if it already has one
+    pub fn set_level(&mut self, target: &str, level: LevelFilter) -> Result<(), FilterError> {
+        if let Some(directive) = self
+            .directives
+            .iter_mut()
+            .find(|directive| directive.target == target)
+        {
+            directive.level = level;
+            return Ok(());
+        }
+        let target = heapless::String::try_from(target).map_err(|_| FilterError::TargetTooLong)?;
+        self.directives
+            .push(Directive { target, level })
+            .map_err(|_| FilterError::TooManyDirectives)
+    }
+    /// `target` falls back to the default level again
+    pub fn clear_level(&mut self, target: &str) {
+        self.directives
+            .retain(|directive| directive.target != target);
+    }
+
+    /// Applies comma separated `target=level` directives on top of the current ones,
+    /// a directive with only a level sets the default level.
+    /// Nothing is changed if any of them is invalid.
+    pub fn apply(&mut self, directives: &str) -> Result<(), FilterError> {
+        let mut filter = self.clone();
+        for directive in directives.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+            match directive.split_once('=') {
+                Some((target, level)) => filter.set_level(target.trim(), parse_level(level)?)?,
+                None => filter.set_default_level(parse_level(directive)?),
+            }
+        }
+        *self = filter;
+        Ok(())
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, FilterError> {
+    LevelFilter::from_str(level.trim()).map_err(|_| FilterError::UnknownLevel)
+}
+
+impl FromStr for LogFilter {
+    type Err = FilterError;
+
+    /// Targets without a directive default to `DEFAULT_LEVEL`
+    fn from_str(directives: &str) -> Result<Self, FilterError> {
+        let mut filter = LogFilter::new(DEFAULT_LEVEL);
+        filter.apply(directives)?;
+        Ok(filter)
+    }
+}
+
+/// Same format `apply` takes, `INFO,kernel::memory=WARN`
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default)?;
+        for directive in &self.directives {
+            write!(f, ",{}={}", directive.target, directive.level)?;
+        }
+        Ok(())
+    }
+}
+
+static FILTER: IrqSafeMutex<LogFilter> = IrqSafeMutex::new(LogFilter::new(DEFAULT_LEVEL));
+
+/// Never waits for `FILTER`: an NMI or debug exception handler that logs could have interrupted
+/// this cpu while it holds it. Until it's released logs use `DEFAULT_LEVEL`.
+pub(super) fn enabled(metadata: &log::Metadata) -> bool {
+    let level = FILTER
+        .try_lock()
+        .map_or(DEFAULT_LEVEL, |filter| filter.level_for(metadata.target()));
+    metadata.level() <= level
+}
+
+// keeps the log macros from skipping levels that some target has enabled
+fn update(
+    change: impl FnOnce(&mut LogFilter) -> Result<(), FilterError>,
+) -> Result<(), FilterError> {
+    let mut filter = FILTER.lock();
+    change(&mut filter)?;
+    log::set_max_level(filter.max_level());
+    Ok(())
+}
+
+pub fn current_filter() -> LogFilter {
+    FILTER.lock().clone()
+}
+pub fn set_filter(filter: LogFilter) {
+    update(|current| {
+        *current = filter;
+        Ok(())
+    })
+    .unwrap();
+}
+/// See `LogFilter::apply`
+pub fn apply_directives(directives: &str) -> Result<(), FilterError> {
+    update(|filter| filter.apply(directives))
+}
+pub fn set_level(target: &str, level: LevelFilter) -> Result<(), FilterError> {
+    update(|filter| filter.set_level(target, level))
+}
+pub fn clear_level(target: &str) {
+    update(|filter| {
+        filter.clear_level(target);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directive(target: &str) -> Directive {
+        Directive {
+            target: heapless::String::try_from(target).unwrap(),
+            level: LevelFilter::Debug,
+        }
+    }
+
+    #[test_case]
+    fn directive_matches_target_and_submodules() {
+        let directive = directive("kernel::memory");
+        assert!(directive.matches("kernel::memory"));
+        assert!(directive.matches("kernel::memory::paging"));
+        assert!(!directive.matches("kernel::memory_map"));
+        assert!(!directive.matches("kernel"));
+    }
+
+    #[test_case]
+    fn most_specific_directive_wins() {
+        let filter: LogFilter = "warn,kernel=info,kernel::memory=trace".parse().unwrap();
+        assert_eq!(filter.level_for("terminal"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("kernel::interrupts"), LevelFilter::Info);
+        assert_eq!(
+            filter.level_for("kernel::memory::paging"),
+            LevelFilter::Trace
+        );
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+    }
+
+    #[test_case]
+    fn invalid_directive_changes_nothing() {
+        let mut filter: LogFilter = "kernel=debug".parse().unwrap();
+        assert_eq!(
+            filter.apply("error,kernel=warn,os=loud"),
+            Err(FilterError::UnknownLevel)
+        );
+        assert_eq!(filter.default_level(), DEFAULT_LEVEL);
+        assert_eq!(filter.level_for("kernel"), LevelFilter::Debug);
+
+        let long_target = "a".repeat(MAX_TARGET_LEN + 1);
+        let directives = alloc::format!("{long_target}=info");
+        assert_eq!(filter.apply(&directives), Err(FilterError::TargetTooLong));
+        assert_eq!(filter.directives.len(), 1);
+    }
+
+    #[test_case]
+    fn display_round_trips() {
+        let filter: LogFilter = "debug,kernel::memory=warn,os=off".parse().unwrap();
+        let text = alloc::format!("{filter}");
+        assert_eq!(text, "DEBUG,kernel::memory=WARN,os=OFF");
+        let parsed: LogFilter = text.parse().unwrap();
+        assert_eq!(alloc::format!("{parsed}"), text);
+        assert_eq!(parsed.level_for("os::graphics"), LevelFilter::Off);
+    }
+
+    #[test_case]
+    fn enabled_doesnt_wait_for_filter() {
+        // same as an NMI that logs while this cpu changes the filter
+        let _filter = FILTER.lock();
+        let metadata = |level| {
+            log::Metadata::builder()
+                .level(level)
+                .target("kernel")
+                .build()
+        };
+        assert!(enabled(&metadata(log::Level::Info)));
+        assert!(!enabled(&metadata(log::Level::Debug)));
+    }
+}
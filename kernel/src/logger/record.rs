@@ -0,0 +1,81 @@
+use alloc::string::String;
+use core::fmt;
+
+use log::Level;
+
+use crate::{task::TaskId, time::Instant};
+
+/// Single log message with everything known about where it came from
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    pub level: Level,
+    /// module path, unless the log macro was given a different target
+    pub target: String,
+    pub timestamp: Instant,
+    /// `None` for logs made before per-cpu data was initialized
+    pub cpu: Option<usize>,
+    /// task that was polled when it was logged
+    pub task: Option<TaskId>,
+    pub message: String,
+}
+
+/// `[   12.345] INFO  cpu0 kernel::memory: message`
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_log_line(
+            f,
+            self.level,
+            self.timestamp,
+            self.cpu,
+            self.task,
+            &self.target,
+            &self.message,
+        )
+    }
+}
+
+/// Borrowed log, fixed size entries and logs formatted on the heap are written the same way
+#[derive(Clone, Copy)]
+pub(crate) struct LogLine<'a> {
+    pub level: Level,
+    pub timestamp: Instant,
+    pub cpu: Option<usize>,
+    pub task: Option<TaskId>,
+    pub target: &'a str,
+    pub message: &'a str,
+}
+
+impl fmt::Display for LogLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_log_line(
+            f,
+            self.level,
+            self.timestamp,
+            self.cpu,
+            self.task,
+            self.target,
+            self.message,
+        )
+    }
+}
+
+pub(super) fn write_log_line(
+    f: &mut impl fmt::Write,
+    level: Level,
+    timestamp: Instant,
+    cpu: Option<usize>,
+    task: Option<TaskId>,
+    target: &str,
+    message: &str,
+) -> fmt::Result {
+    let ms = timestamp.as_ms();
+    write!(f, "[{:>5}.{:03}] {:5} ", ms / 1000, ms % 1000, level)?;
+    match cpu {
+        Some(cpu) => write!(f, "cpu{cpu} ")?,
+        None => write!(f, "cpu? ")?,
+    }
+    if let Some(task) = task {
+        write!(f, "task{} ", task.as_u64())?;
+    }
+    write!(f, "{target}: {message}")
+}
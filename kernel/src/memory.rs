@@ -15,7 +15,7 @@ pub const HEAP_SIZE: usize = 10000 * 1024; // 10000 KiB
 pub const ACPI_MEMORY_SIZE: usize = 4 * 8 * 1024 * 2;
 pub const ACPI_START_ADDRESS: usize = HEAP_START + HEAP_SIZE + 1;
 
-pub const PER_AP_STACK_STACK_SIZE: usize = 16383; // 16 KiB per core
+pub const PER_AP_STACK_STACK_SIZE: usize = 16 * 1024; // 16 KiB per core
 
 pub const AP_STACK_MEMORY_START: usize = ACPI_START_ADDRESS + ACPI_MEMORY_SIZE; // skip 1 page - 4 KiB
 
@@ -57,20 +57,22 @@ pub unsafe fn init_mem(
 
     level_4_table_phys_address
 }
+fn page_range(start: usize, size: usize) -> PageRangeInclusive {
+    let heap_start = VirtAddr::new(start as u64);
+    debug!("start: {heap_start:?}");
+    let heap_end = heap_start + size as u64 - 1u64;
+    let heap_start_page = Page::containing_address(heap_start);
+    let heap_end_page = Page::containing_address(heap_end);
+    Page::range_inclusive(heap_start_page, heap_end_page)
+}
+
 /// # Safety
 /// invalid memory address could lead to unexpected behavior
 pub unsafe fn map_memory(start: usize, size: usize, flags: PageTableFlags) {
     let mut frame_allocator = StaticFrameAllocator {};
     let mut mapper = MAPPER.get().expect("Memory was not yet initialized").lock();
 
-    let page_range = {
-        let heap_start = VirtAddr::new(start as u64);
-        debug!("start: {heap_start:?}");
-        let heap_end = heap_start + size as u64 - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
+    let page_range = page_range(start, size);
     for page in page_range {
         debug!("map page: {page:?}");
         let frame = frame_allocator.allocate_frame().unwrap();
@@ -81,8 +83,75 @@ pub unsafe fn map_memory(start: usize, size: usize, flags: PageTableFlags) {
                 .flush()
         };
     }
+    // no shootdown, the pages weren't mapped so no cpu could have cached them
+}
+
+/// Frames are not given back to the frame allocator, it can't reuse them.
+/// # Safety
+/// nothing can use this memory anymore, on any cpu
+pub unsafe fn unmap_memory(start: usize, size: usize) {
+    let mut mapper = MAPPER.get().expect("Memory was not yet initialized").lock();
+
+    let page_range = page_range(start, size);
+    for page in page_range {
+        debug!("unmap page: {page:?}");
+        let (_frame, flush) = mapper
+            .unmap(page)
+            .expect("unmapping memory did not succeed");
+        flush.flush();
+    }
+    // other cpus might wait for the mapper while their interrupts are disabled
+    drop(mapper);
+    shootdown_tlb(page_range);
 }
 
+// above this it's cheaper to flush the whole TLB
+const MAX_PAGES_TO_FLUSH: usize = 32;
+
+/// Flushes pages from TLB of all other cpus, this cpu is flushed by the mapper itself.
+/// Only needed when a present mapping is removed or changed.
+fn shootdown_tlb(page_range: PageRangeInclusive) {
+    if crate::threads::online_cpus() <= 1 {
+        return;
+    }
+    crate::interrupts::ipi::run_on_other_cpus(|| {
+        if page_range.count() > MAX_PAGES_TO_FLUSH {
+            tlb::flush_all();
+            return;
+        }
+        for page in page_range {
+            tlb::flush(page.start_address());
+        }
+    });
+}
+
+/// Maps virtual memory to the same physical addresses.
+/// # Safety
+/// caller has to make sure that nothing else uses those physical frames
+pub unsafe fn identity_map_memory(start: usize, size: usize, flags: PageTableFlags) {
+    let mut frame_allocator = StaticFrameAllocator {};
+    let mut mapper = MAPPER.get().expect("Memory was not yet initialized").lock();
+
+    let start_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(start as u64));
+    let end_frame =
+        PhysFrame::<Size4KiB>::containing_address(PhysAddr::new((start + size - 1) as u64));
+    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+        debug!("identity map frame: {frame:?}");
+        unsafe {
+            mapper
+                .identity_map(frame, flags, &mut frame_allocator)
+                .unwrap()
+                .flush()
+        };
+    }
+}
+
+/// Frames below 1 MiB are never handed out by the frame allocator.
+/// Real mode code (eg. AP trampoline) has to live there.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::page::PageRangeInclusive;
 use x86_64::structures::paging::{
     FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB,
 };
@@ -103,8 +172,8 @@ impl StaticFrames {
         let regions = self.memory_map.iter();
 
         let usable_regions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.start..r.end);
+        // map each region to its address range, skipping low memory
+        let addr_ranges = usable_regions.map(|r| r.start.max(LOW_MEMORY_END)..r.end);
         // transform to an iterator of frame start addresses
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         // create `PhysFrame` types from the start addresses
@@ -133,3 +202,48 @@ pub fn init_static(memory_map: bootloader_api::info::MemoryRegions) {
     };
     FRAMES.init_once(|| Mutex::new(static_version));
 }
+
+#[cfg(test)]
+mod tests {
+    use x86_64::structures::paging::{PageTableFlags, Translate};
+
+    use super::*;
+
+    // nothing else is mapped there
+    const TEST_MEMORY_START: usize = 0x_5555_5555_0000;
+
+    #[test_case]
+    fn heap_is_mapped() {
+        let mapper = MAPPER.get().unwrap().lock();
+        for address in [HEAP_START, HEAP_START + HEAP_SIZE - 1] {
+            assert!(
+                mapper
+                    .translate_addr(VirtAddr::new(address as u64))
+                    .is_some()
+            );
+        }
+    }
+
+    #[test_case]
+    fn map_and_unmap() {
+        let size = 2 * 4096;
+        unsafe {
+            map_memory(
+                TEST_MEMORY_START,
+                size,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            )
+        };
+        let memory = unsafe { core::slice::from_raw_parts_mut(TEST_MEMORY_START as *mut u8, size) };
+        memory.fill(0xAB);
+        assert!(memory.iter().all(|byte| *byte == 0xAB));
+
+        unsafe { unmap_memory(TEST_MEMORY_START, size) };
+        let mapper = MAPPER.get().unwrap().lock();
+        assert!(
+            mapper
+                .translate_addr(VirtAddr::new(TEST_MEMORY_START as u64))
+                .is_none()
+        );
+    }
+}
@@ -1,27 +1,52 @@
-#[cfg(not(test))]
 use core::panic::PanicInfo;
 
 use crate::hlt_loop;
 use log::*;
 //  run on panic
-#[cfg(not(test))] // new attribute
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    error!("{}\n", info);
-    error!("{}", info);
+    let task = crate::percpu::try_this_cpu()
+        .filter(|cpu| !cpu.in_interrupt())
+        .and_then(|cpu| Some((cpu, cpu.current_task()?)));
+    if crate::testing::is_running() && !(task.is_some() && crate::testing::take_expected_panic()) {
+        crate::testing::fail(info);
+    }
+    crate::logger::panic_log(format_args!("{info}"));
+
+    // panic inside of a task only kills the task, this cpu goes back to running other ones
+    if let Some((cpu, task_id)) = task {
+        error!("task {task_id:?} panicked on cpu {}", cpu.index);
+        cpu.set_current_task(None);
+        crate::task::executor::TASK_SPAWNER.on_task_panic(cpu);
+        unsafe { restart_executor(cpu.scratch_stack_top.as_u64()) };
+    }
+    // same for kernel threads, except for the main ones that run executors
+    if let Some(cpu) = crate::percpu::try_this_cpu()
+        && !cpu.in_interrupt()
+        && let Some(thread) = crate::thread::current()
+        && !thread.is_main()
+    {
+        error!("thread {:?} panicked on cpu {}", thread.name(), cpu.index);
+        drop(thread);
+        crate::thread::exit();
+    }
 
     hlt_loop();
 }
 
-// our panic handler in test mode
-
-#[cfg(test)]
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    use crate::qemu::{QemuExitCode, exit_qemu};
-
-    error!("[failed]\n");
-    error!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
-    hlt_loop();
+/// Abandons the current stack (there is no unwinding) and starts a fresh executor loop on `stack_top`
+unsafe fn restart_executor(stack_top: u64) -> ! {
+    extern "C" fn executor_entry() -> ! {
+        crate::task::executor::Executor::new().run();
+    }
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {stack_top}",
+            "call {entry}",
+            stack_top = in(reg) stack_top,
+            entry = sym executor_entry,
+            options(noreturn)
+        )
+    }
 }
+
@@ -0,0 +1,168 @@
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::{boxed::Box, vec};
+use crossbeam_queue::ArrayQueue;
+use x86_64::{
+    VirtAddr,
+    registers::model_specific::{GsBase, KernelGsBase},
+};
+
+use crate::{
+    interrupts::ipi::{CALL_QUEUE_SIZE, CallPtr},
+    logger::LogBuffer,
+    task::{TaskId, executor::ReadyQueue},
+    thread::scheduler::Scheduler,
+};
+
+pub const MAX_CPUS: usize = 64;
+const SCRATCH_STACK_SIZE: usize = 4096 * 4;
+// `current_task` value when cpu isn't polling any task
+const NO_TASK: u64 = u64::MAX;
+
+/// Data owned by a single cpu, reached through GS base.
+/// Everything is either immutable or atomic, so `&'static PerCpu` can be freely shared with
+/// other cores (eg. for work stealing).
+#[repr(C)]
+pub struct PerCpu {
+    // WARN: has to stay first, `this_cpu` reads it from gs:[0]
+    self_ptr: *const PerCpu,
+    /// index in `threads::CPUS`, 0 -> bootstrap processor
+    pub index: usize,
+    pub apic_id: u32,
+    current_task: AtomicU64,
+    interrupt_depth: AtomicUsize,
+    // sleeping in `hlt` because none of its threads is ready, has to be woken by IPI
+    idle: AtomicBool,
+    /// tasks that are ready to be polled by this cpu
+    pub run_queue: ReadyQueue,
+    /// top of a small stack that is free to use by this cpu (eg. when the current one can't be trusted)
+    pub scratch_stack_top: VirtAddr,
+    // cross cpu calls waiting to be run by this cpu, fixed size so IPI handler never frees memory
+    pub(crate) call_queue: ArrayQueue<CallPtr>,
+    pub(crate) scheduler: Scheduler,
+    // logs waiting to be written to serial, interrupt handlers can log without taking any lock
+    pub(crate) log_buffer: LogBuffer,
+}
+unsafe impl Sync for PerCpu {}
+unsafe impl Send for PerCpu {}
+
+impl PerCpu {
+    pub fn current_task(&self) -> Option<TaskId> {
+        match self.current_task.load(Ordering::Relaxed) {
+            NO_TASK => None,
+            id => Some(TaskId::from_u64(id)),
+        }
+    }
+    pub fn set_current_task(&self, task_id: Option<TaskId>) {
+        let id = task_id.map_or(NO_TASK, TaskId::as_u64);
+        self.current_task.store(id, Ordering::Relaxed);
+    }
+
+    /// how many interrupt handlers are currently running on this cpu
+    pub fn interrupt_depth(&self) -> usize {
+        self.interrupt_depth.load(Ordering::Relaxed)
+    }
+    pub fn in_interrupt(&self) -> bool {
+        self.interrupt_depth() > 0
+    }
+    // every thread has its own depth (it can be switched out inside of the timer handler)
+    pub(crate) fn set_interrupt_depth(&self, depth: usize) {
+        self.interrupt_depth.store(depth, Ordering::Relaxed);
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle.load(Ordering::SeqCst)
+    }
+    pub(crate) fn set_idle(&self, idle: bool) {
+        self.idle.store(idle, Ordering::SeqCst);
+    }
+}
+
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+/// Allocates per-cpu data and points GS base at it.
+/// Has to be called once by every cpu, before it enables interrupts.
+pub fn init(index: usize, apic_id: u32) {
+    assert!(
+        index < MAX_CPUS,
+        "cpu index: {index} is too big, max: {MAX_CPUS}"
+    );
+
+    let scratch_stack = Box::leak(vec![0u8; SCRATCH_STACK_SIZE].into_boxed_slice());
+    let scratch_stack_top = VirtAddr::from_ptr(scratch_stack.as_ptr()) + SCRATCH_STACK_SIZE as u64;
+
+    let per_cpu = Box::leak(Box::new(PerCpu {
+        self_ptr: ptr::null(),
+        index,
+        apic_id,
+        current_task: AtomicU64::new(NO_TASK),
+        interrupt_depth: AtomicUsize::new(0),
+        idle: AtomicBool::new(false),
+        run_queue: ReadyQueue::new(),
+        scratch_stack_top: VirtAddr::new(scratch_stack_top.as_u64() & !0xF),
+        call_queue: ArrayQueue::new(CALL_QUEUE_SIZE),
+        scheduler: Scheduler::new(),
+        log_buffer: LogBuffer::new(),
+    }));
+    per_cpu.self_ptr = per_cpu;
+
+    let address = VirtAddr::from_ptr(per_cpu);
+    GsBase::write(address);
+    // so it stays reachable after `swapgs`
+    KernelGsBase::write(address);
+
+    CPUS[index].store(per_cpu, Ordering::Release);
+}
+
+/// Per-cpu data of the cpu that calls this.
+/// Safe to use from interrupt handlers, every cpu initializes it before enabling interrupts.
+pub fn this_cpu() -> &'static PerCpu {
+    let per_cpu: *const PerCpu;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, gs:[0]",
+            out(reg) per_cpu,
+            options(nostack, readonly, preserves_flags)
+        )
+    };
+    unsafe { &*per_cpu }
+}
+
+/// Like `this_cpu` but returns `None` instead of faulting when called before `init`
+/// (eg. by early logs).
+pub fn try_this_cpu() -> Option<&'static PerCpu> {
+    if GsBase::read().is_null() {
+        return None;
+    }
+    Some(this_cpu())
+}
+
+/// Per-cpu data of any initialized cpu
+pub fn cpu(index: usize) -> Option<&'static PerCpu> {
+    let per_cpu = CPUS.get(index)?.load(Ordering::Acquire);
+    unsafe { per_cpu.as_ref() }
+}
+
+pub fn all_cpus() -> impl Iterator<Item = &'static PerCpu> {
+    (0..MAX_CPUS).filter_map(cpu)
+}
+
+/// Counts nested interrupt handlers on this cpu, create it at the start of a handler.
+pub struct InterruptGuard {
+    per_cpu: &'static PerCpu,
+}
+impl InterruptGuard {
+    pub fn enter() -> InterruptGuard {
+        let per_cpu = this_cpu();
+        per_cpu.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+        InterruptGuard { per_cpu }
+    }
+}
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        self.per_cpu.interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -1,29 +1,74 @@
+mod config;
+mod stream;
+mod uart;
+
 use core::fmt;
 
+pub use config::{ConfigError, DataBits, LineConfig, Parity, StopBits};
+pub(crate) use stream::on_interrupt;
+pub use stream::{SerialStream, try_write, write};
+pub use uart::{InterruptCause, Uart};
+
+use crate::boot_config;
+
+/// COM1, the one QEMU connects to `-serial`
+pub(crate) const COM1: Uart = unsafe { Uart::new(0x3F8) };
+
+/// Polling writer of COM1, used by the logger. Doesn't depend on interrupts, so it works during
+/// early boot and in the panic handler.
 pub struct SerialPort {
-    port: uart_16550::SerialPort,
+    uart: Uart,
 }
 
 impl SerialPort {
+    /// Line settings come from `serial=<config>` in the boot config (see `LineConfig`),
+    /// `115200,8N1` by default
     /// # Safety
     ///
     /// unsafe because this function must only be called once
     pub unsafe fn init() -> Self {
-        let mut port = unsafe { uart_16550::SerialPort::new(0x3F8) };
-        port.init();
-        Self { port }
+        COM1.init(boot_config::get().serial)
+            .expect("line config from the boot config was validated when parsed");
+        Self { uart: COM1 }
+    }
+
+    /// Second handle to the already initialized COM1, for the panic handler when the logger
+    /// holds the first one.
+    /// # Safety
+    ///
+    /// output gets mixed with anything else written at the same time
+    pub unsafe fn emergency() -> Self {
+        Self { uart: COM1 }
+    }
+
+    pub fn configure(&mut self, config: LineConfig) -> Result<(), ConfigError> {
+        config.divisor()?;
+        // keeps the interrupt handler away from the data register while it holds the divisor
+        let _tx_queue = stream::TX_QUEUE.lock();
+        let interrupts = self.uart.interrupts();
+        self.uart.set_interrupts(0);
+        let result = self.uart.configure(config);
+        self.uart.set_interrupts(interrupts);
+        result
     }
 }
 
+/// Changes baud rate and line settings of COM1 once the logger is done writing to it
+pub fn configure(config: LineConfig) -> Result<(), ConfigError> {
+    crate::logger::with_serial_port(|port| port.configure(config))
+}
+
 impl fmt::Write for SerialPort {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for char in s.bytes() {
             match char {
-                b'\n' => self.port.write_str("\r\n").unwrap(),
-                byte => self.port.send(byte),
+                b'\n' => {
+                    self.uart.send_blocking(b'\r');
+                    self.uart.send_blocking(b'\n');
+                }
+                byte => self.uart.send_blocking(byte),
             }
         }
         Ok(())
     }
 }
-
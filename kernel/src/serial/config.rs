@@ -0,0 +1,135 @@
+use core::str::FromStr;
+
+/// Baud rates are derived from this clock, divided by a 16 bit divisor
+const UART_CLOCK_HZ: u32 = 115_200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// `115_200` has to be divisible by it
+    InvalidBaudRate,
+    InvalidFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// always 1
+    Mark,
+    /// always 0
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    /// 1.5 with five data bits
+    Two,
+}
+
+/// Baud rate and frame format, written as `115200,8N1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl Default for LineConfig {
+    /// `115200,8N1`, what QEMU and most terminals expect
+    fn default() -> Self {
+        LineConfig {
+            baud_rate: UART_CLOCK_HZ,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+impl LineConfig {
+    pub fn divisor(&self) -> Result<u16, ConfigError> {
+        if self.baud_rate == 0 || !UART_CLOCK_HZ.is_multiple_of(self.baud_rate) {
+            return Err(ConfigError::InvalidBaudRate);
+        }
+        u16::try_from(UART_CLOCK_HZ / self.baud_rate).map_err(|_| ConfigError::InvalidBaudRate)
+    }
+
+    pub(super) fn line_control(&self) -> u8 {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 0b00,
+            DataBits::Six => 0b01,
+            DataBits::Seven => 0b10,
+            DataBits::Eight => 0b11,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1 << 2,
+        };
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Odd => 0b001 << 3,
+            Parity::Even => 0b011 << 3,
+            Parity::Mark => 0b101 << 3,
+            Parity::Space => 0b111 << 3,
+        };
+        data_bits | stop_bits | parity
+    }
+}
+
+impl FromStr for LineConfig {
+    type Err = ConfigError;
+
+    /// `<baud>` or `<baud>,<data bits><parity N/O/E/M/S><stop bits>`, eg. `9600,7E2`
+    fn from_str(config: &str) -> Result<Self, ConfigError> {
+        let (baud_rate, format) = match config.split_once(',') {
+            Some((baud_rate, format)) => (baud_rate, Some(format)),
+            None => (config, None),
+        };
+        let mut line_config = LineConfig {
+            baud_rate: baud_rate
+                .parse()
+                .map_err(|_| ConfigError::InvalidBaudRate)?,
+            ..LineConfig::default()
+        };
+        line_config.divisor()?;
+
+        let Some(format) = format else {
+            return Ok(line_config);
+        };
+        let &[data_bits, parity, stop_bits] = format.as_bytes() else {
+            return Err(ConfigError::InvalidFormat);
+        };
+        line_config.data_bits = match data_bits {
+            b'5' => DataBits::Five,
+            b'6' => DataBits::Six,
+            b'7' => DataBits::Seven,
+            b'8' => DataBits::Eight,
+            _ => return Err(ConfigError::InvalidFormat),
+        };
+        line_config.parity = match parity.to_ascii_uppercase() {
+            b'N' => Parity::None,
+            b'O' => Parity::Odd,
+            b'E' => Parity::Even,
+            b'M' => Parity::Mark,
+            b'S' => Parity::Space,
+            _ => return Err(ConfigError::InvalidFormat),
+        };
+        line_config.stop_bits = match stop_bits {
+            b'1' => StopBits::One,
+            b'2' => StopBits::Two,
+            _ => return Err(ConfigError::InvalidFormat),
+        };
+        Ok(line_config)
+    }
+}
@@ -0,0 +1,154 @@
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+use log::*;
+
+use super::{
+    COM1,
+    uart::{FIFO_SIZE, INTERRUPT_THR_EMPTY, InterruptCause},
+};
+use crate::{sync::IrqSafeMutex, task::sync::Mutex};
+
+const RX_BUFFER_SIZE: usize = 256;
+const TX_BUFFER_SIZE: usize = 1024;
+
+static RX_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static RX_WAKER: AtomicWaker = AtomicWaker::new();
+
+// bytes waiting for the transmitter, sent from the interrupt handler
+pub(super) static TX_QUEUE: IrqSafeMutex<heapless::Deque<u8, TX_BUFFER_SIZE>> =
+    IrqSafeMutex::new(heapless::Deque::new());
+static TX_WAKER: AtomicWaker = AtomicWaker::new();
+// one writer at a time, so their output doesn't get mixed
+static WRITER: Mutex<()> = Mutex::new(());
+
+/// Called by the COM1 interrupt handler
+///
+/// Must not block or allocate.
+pub(crate) fn on_interrupt() {
+    while let Some(cause) = COM1.interrupt_cause() {
+        match cause {
+            InterruptCause::ReceivedData | InterruptCause::CharacterTimeout => receive(),
+            InterruptCause::TransmitterEmpty => transmit(),
+            InterruptCause::LineStatus => {
+                warn!("serial line error, status: {:#x}", COM1.line_status());
+            }
+            InterruptCause::ModemStatus => {
+                COM1.modem_status();
+            }
+        }
+    }
+}
+
+fn receive() {
+    // bytes have to be read either way, otherwise the interrupt fires again
+    while let Some(byte) = COM1.try_receive() {
+        // nobody reads serial input yet
+        let Ok(queue) = RX_QUEUE.try_get() else {
+            continue;
+        };
+        if queue.push(byte).is_err() {
+            warn!("serial input queue full; dropping input");
+        }
+    }
+    RX_WAKER.wake();
+}
+
+fn transmit() {
+    let mut queue = TX_QUEUE.lock();
+    // one less than the FIFO holds, the logger can write a byte at the same time
+    for _ in 0..FIFO_SIZE - 1 {
+        match queue.pop_front() {
+            Some(byte) => COM1.send_unchecked(byte),
+            None => {
+                // nothing left, otherwise it fires every time the FIFO empties
+                COM1.set_interrupts(COM1.interrupts() & !INTERRUPT_THR_EMPTY);
+                break;
+            }
+        }
+    }
+    TX_WAKER.wake();
+}
+
+/// Queues as much of `bytes` as fits, returns how many did
+pub fn try_write(bytes: &[u8]) -> usize {
+    let mut queue = TX_QUEUE.lock();
+    let written = bytes
+        .iter()
+        .take_while(|byte| queue.push_back(**byte).is_ok())
+        .count();
+    // interrupt fires right away if the transmitter is already empty
+    if written > 0 {
+        COM1.set_interrupts(COM1.interrupts() | INTERRUPT_THR_EMPTY);
+    }
+    written
+}
+
+/// Sends `bytes` through COM1 without blocking the cpu, waits while the transmit buffer is full
+pub async fn write(bytes: &[u8]) {
+    let _writer = WRITER.lock().await;
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let written = futures_util::future::poll_fn(|cx| {
+            // fast path
+            let written = try_write(rest);
+            if written > 0 {
+                return Poll::Ready(written);
+            }
+            TX_WAKER.register(cx.waker());
+            match try_write(rest) {
+                0 => Poll::Pending,
+                written => Poll::Ready(written),
+            }
+        })
+        .await;
+        rest = &rest[written..];
+    }
+}
+
+/// Bytes received by COM1
+pub struct SerialStream {
+    _private: (),
+}
+
+impl SerialStream {
+    pub fn new() -> Self {
+        RX_QUEUE
+            .try_init_once(|| ArrayQueue::new(RX_BUFFER_SIZE))
+            .expect("SerialStream::new should only be called once");
+        SerialStream { _private: () }
+    }
+
+    /// Same as `StreamExt::next`, for users that don't depend on futures
+    pub async fn read_byte(&mut self) -> u8 {
+        futures_util::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx))
+            .await
+            .expect("serial stream never ends")
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = RX_QUEUE.try_get().expect("serial queue not initialized");
+
+        // fast path
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        RX_WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(byte) => {
+                RX_WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use x86_64::instructions::port::Port;
+
+use super::config::{ConfigError, LineConfig};
+
+// register offsets from the base port
+const DATA: u16 = 0; // divisor low byte while DLAB is set
+const INTERRUPT_ENABLE: u16 = 1; // divisor high byte while DLAB is set
+const FIFO_CONTROL: u16 = 2; // interrupt identification when read
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+
+const DLAB: u8 = 1 << 7;
+const LINE_STATUS_DATA_READY: u8 = 1;
+const LINE_STATUS_THR_EMPTY: u8 = 1 << 5;
+// enable + clear both FIFOs + receive interrupt after 14 bytes
+const FIFO_ENABLE: u8 = 0xC7;
+// DTR + RTS + OUT2, without OUT2 the interrupt line is never raised
+const MODEM_CONTROL_DEFAULT: u8 = 0x0B;
+
+pub const INTERRUPT_RECEIVED: u8 = 1;
+pub const INTERRUPT_THR_EMPTY: u8 = 1 << 1;
+pub const INTERRUPT_LINE_STATUS: u8 = 1 << 2;
+
+/// How many bytes can be written after `THR empty` without checking the line status again
+pub const FIFO_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptCause {
+    LineStatus,
+    ReceivedData,
+    // data waits in the FIFO, but less than the interrupt threshold
+    CharacterTimeout,
+    TransmitterEmpty,
+    ModemStatus,
+}
+
+/// 16550 UART registers. Only a port number, so it's freely copied between the logger,
+/// the interrupt handler and the panic handler.
+#[derive(Debug, Clone, Copy)]
+pub struct Uart {
+    base: u16,
+}
+
+impl Uart {
+    /// # Safety
+    /// `base` has to be the first port of a 16550 compatible UART
+    pub const unsafe fn new(base: u16) -> Self {
+        Uart { base }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base + register).read() }
+    }
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.base + register).write(value) }
+    }
+
+    /// Leaves received data and line status interrupts enabled
+    pub fn init(&self, config: LineConfig) -> Result<(), ConfigError> {
+        self.write(INTERRUPT_ENABLE, 0);
+        self.configure(config)?;
+        self.write(FIFO_CONTROL, FIFO_ENABLE);
+        self.write(MODEM_CONTROL, MODEM_CONTROL_DEFAULT);
+        self.set_interrupts(INTERRUPT_RECEIVED | INTERRUPT_LINE_STATUS);
+        Ok(())
+    }
+
+    /// WARN: nothing else can use the data register in the meantime, it's the divisor for a moment
+    pub fn configure(&self, config: LineConfig) -> Result<(), ConfigError> {
+        let divisor = config.divisor()?;
+        self.write(LINE_CONTROL, DLAB);
+        self.write(DATA, divisor as u8);
+        self.write(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write(LINE_CONTROL, config.line_control());
+        Ok(())
+    }
+
+    pub fn interrupts(&self) -> u8 {
+        self.read(INTERRUPT_ENABLE)
+    }
+    pub fn set_interrupts(&self, interrupts: u8) {
+        self.write(INTERRUPT_ENABLE, interrupts);
+    }
+
+    /// Highest priority interrupt that is pending, the next one is reported once this is handled
+    pub fn interrupt_cause(&self) -> Option<InterruptCause> {
+        let identification = self.read(FIFO_CONTROL);
+        // bit 0 is clear while some interrupt is pending
+        if identification & 1 != 0 {
+            return None;
+        }
+        match (identification >> 1) & 0b111 {
+            0b011 => Some(InterruptCause::LineStatus),
+            0b010 => Some(InterruptCause::ReceivedData),
+            0b110 => Some(InterruptCause::CharacterTimeout),
+            0b001 => Some(InterruptCause::TransmitterEmpty),
+            _ => Some(InterruptCause::ModemStatus),
+        }
+    }
+    /// Reading these clears line and modem status interrupts
+    pub fn line_status(&self) -> u8 {
+        self.read(LINE_STATUS)
+    }
+    pub fn modem_status(&self) -> u8 {
+        self.read(MODEM_STATUS)
+    }
+
+    pub fn is_transmitter_empty(&self) -> bool {
+        self.line_status() & LINE_STATUS_THR_EMPTY != 0
+    }
+    /// Spins until the transmitter is empty, works with interrupts disabled
+    pub fn send_blocking(&self, byte: u8) {
+        while !self.is_transmitter_empty() {
+            core::hint::spin_loop();
+        }
+        self.write(DATA, byte);
+    }
+    /// Doesn't check whether there is space, see `FIFO_SIZE`
+    pub fn send_unchecked(&self, byte: u8) {
+        self.write(DATA, byte);
+    }
+    pub fn try_receive(&self) -> Option<u8> {
+        if self.line_status() & LINE_STATUS_DATA_READY == 0 {
+            return None;
+        }
+        Some(self.read(DATA))
+    }
+}
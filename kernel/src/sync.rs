@@ -0,0 +1,263 @@
+use alloc::sync::Arc;
+use core::ops::{Deref, DerefMut};
+
+use x86_64::instructions::interrupts;
+
+/// Spinlock that disables interrupts while it's held and restores the previous state afterwards.
+/// Use it for everything that can be touched from an interrupt handler, otherwise the handler can
+/// spin forever on a lock held by the code it interrupted.
+pub struct IrqSafeMutex<T> {
+    inner: spin::Mutex<T>,
+    #[cfg(feature = "lock_debug")]
+    debug: debug::LockDebug,
+}
+
+unsafe impl<T: Send> Sync for IrqSafeMutex<T> {}
+unsafe impl<T: Send> Send for IrqSafeMutex<T> {}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSafeMutex {
+            inner: spin::Mutex::new(value),
+            #[cfg(feature = "lock_debug")]
+            debug: debug::LockDebug::new(),
+        }
+    }
+
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        #[cfg(feature = "lock_debug")]
+        self.debug.before_lock(self as *const _ as usize);
+
+        let guard = self.inner.lock();
+
+        #[cfg(feature = "lock_debug")]
+        self.debug.after_lock();
+
+        IrqSafeMutexGuard {
+            guard: Some(guard),
+            interrupts_were_enabled,
+            #[cfg(feature = "lock_debug")]
+            debug: &self.debug,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        match self.inner.try_lock() {
+            Some(guard) => {
+                #[cfg(feature = "lock_debug")]
+                self.debug.after_lock();
+
+                Some(IrqSafeMutexGuard {
+                    guard: Some(guard),
+                    interrupts_were_enabled,
+                    #[cfg(feature = "lock_debug")]
+                    debug: &self.debug,
+                })
+            }
+            None => {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+pub struct IrqSafeMutexGuard<'a, T> {
+    // in option so it can be released before interrupts are enabled again
+    guard: Option<spin::MutexGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+    #[cfg(feature = "lock_debug")]
+    debug: &'a debug::LockDebug,
+}
+
+impl<T> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+impl<T> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lock_debug")]
+        let held_ticks = self.debug.before_unlock();
+
+        drop(self.guard.take());
+
+        #[cfg(feature = "lock_debug")]
+        debug::report_hold_time(held_ticks);
+
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Callbacks that are called on every event (eg. log record, key press).
+/// Registering replaces the whole list, so an event only clones the `Arc`, and the listeners run
+/// without the lock, with interrupts enabled.
+pub struct Listeners<F> {
+    // `None` until the first one is registered, `Arc` can't be created in a const
+    list: IrqSafeMutex<Option<Arc<[F]>>>,
+}
+
+impl<F: Copy> Listeners<F> {
+    pub const fn new() -> Self {
+        Listeners {
+            list: IrqSafeMutex::new(None),
+        }
+    }
+
+    pub fn register(&self, listener: F) {
+        let mut list = self.list.lock();
+        let listeners = list.iter().flat_map(|list| list.iter().copied());
+        *list = Some(listeners.chain(core::iter::once(listener)).collect());
+    }
+
+    /// Calls `call` with every listener registered when the event happened
+    pub fn call_each(&self, mut call: impl FnMut(F)) {
+        let Some(listeners) = self.list.lock().clone() else {
+            return;
+        };
+        for listener in listeners.iter() {
+            call(*listener);
+        }
+    }
+}
+
+impl<F: Copy> Default for Listeners<F> {
+    fn default() -> Self {
+        Listeners::new()
+    }
+}
+
+#[cfg(feature = "lock_debug")]
+mod debug {
+    use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+    use crate::percpu;
+
+    const NO_OWNER: usize = usize::MAX;
+    /// holding lock for longer than this gets logged
+    const MAX_HOLD_TIME_US: u64 = 1_000;
+
+    pub struct LockDebug {
+        // apic id, see `current_cpu`
+        owner_cpu: AtomicUsize,
+        acquired_at_tsc: AtomicU64,
+    }
+
+    // apic id instead of index, so APs that didn't set up per-cpu data yet aren't mistaken for
+    // the bootstrap processor (cpuid is slow, so it's only used until then)
+    fn current_cpu() -> usize {
+        let apic_id = percpu::try_this_cpu().map_or_else(
+            || {
+                x86::cpuid::CpuId::new()
+                    .get_feature_info()
+                    .expect("cpuid feature info is not available")
+                    .initial_local_apic_id() as u32
+            },
+            |cpu| cpu.apic_id,
+        );
+        apic_id as usize
+    }
+
+    fn rdtsc() -> u64 {
+        unsafe { x86::time::rdtsc() }
+    }
+
+    impl LockDebug {
+        pub const fn new() -> Self {
+            LockDebug {
+                owner_cpu: AtomicUsize::new(NO_OWNER),
+                acquired_at_tsc: AtomicU64::new(0),
+            }
+        }
+
+        /// Interrupts are disabled while lock is held, so the only way for the owner to take it
+        /// again is recursion, which would spin forever.
+        pub fn before_lock(&self, lock_address: usize) {
+            if self.owner_cpu.load(Ordering::Acquire) == current_cpu() {
+                panic!("recursive acquisition of lock at: {lock_address:#x}");
+            }
+        }
+
+        pub fn after_lock(&self) {
+            self.owner_cpu.store(current_cpu(), Ordering::Release);
+            self.acquired_at_tsc.store(rdtsc(), Ordering::Relaxed);
+        }
+
+        /// Returns for how many TSC ticks the lock was held
+        pub fn before_unlock(&self) -> u64 {
+            self.owner_cpu.store(NO_OWNER, Ordering::Release);
+            rdtsc() - self.acquired_at_tsc.load(Ordering::Relaxed)
+        }
+    }
+
+    // logger takes locks too (and writing to serial is slow), don't report those recursively
+    static REPORTING: AtomicBool = AtomicBool::new(false);
+
+    /// Called after the lock is released, so logging can't deadlock on the lock itself
+    pub fn report_hold_time(held_ticks: u64) {
+        let Ok(tsc_hz) = crate::interrupts::TSC_HZ.try_get() else {
+            return;
+        };
+        let held_us = held_ticks * 1_000_000 / tsc_hz;
+        if held_us <= MAX_HOLD_TIME_US || REPORTING.swap(true, Ordering::Acquire) {
+            return;
+        }
+        // log prefix tells the cpu
+        log::warn!("lock was held for {held_us} us");
+        REPORTING.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::Listeners;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn add_one(calls: &AtomicUsize) {
+        calls.fetch_add(1, Ordering::Relaxed);
+    }
+    fn add_ten(calls: &AtomicUsize) {
+        calls.fetch_add(10, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn listeners_are_called_in_order_of_registration() {
+        let listeners: Listeners<fn(&AtomicUsize)> = Listeners::new();
+        listeners.call_each(|listener| listener(&CALLS));
+        assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+
+        listeners.register(add_one);
+        listeners.register(add_ten);
+        let mut order = alloc::vec::Vec::new();
+        listeners.call_each(|listener| {
+            listener(&CALLS);
+            order.push(CALLS.load(Ordering::Relaxed));
+        });
+        assert_eq!(order, [1, 11]);
+    }
+}
@@ -1,37 +1,175 @@
+pub mod combinators;
 pub mod executor;
+pub mod future_set;
+pub mod join;
 pub mod keyboard;
 pub mod simple_executor;
+pub mod sync;
 
-use alloc::boxed::Box;
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
 use core::task::Context;
 use core::{future::Future, pin::Pin};
 use spin::Mutex;
 
+use crate::{percpu, time};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct TaskId(u64);
 
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+    pub(crate) fn from_u64(id: u64) -> Self {
+        TaskId(id)
+    }
+}
+
+/// Executors always poll ready tasks with higher priority first, so low priority ones can
+/// starve when the higher ones never stop waking up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TaskPriority {
+    /// work moved out of interrupt handlers (eg. keyboard scancodes, timer ticks)
+    InterruptBottomHalf,
+    /// anything the user waits on
+    Interactive,
+    /// runs only when nothing else is ready
+    Background,
+}
+impl TaskPriority {
+    pub const COUNT: usize = 3;
+    /// from the highest to the lowest
+    pub const ALL: [TaskPriority; TaskPriority::COUNT] = [
+        TaskPriority::InterruptBottomHalf,
+        TaskPriority::Interactive,
+        TaskPriority::Background,
+    ];
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Updated by executors, only read by `task_list`
+#[derive(Default)]
+struct TaskStats {
+    poll_count: AtomicU64,
+    poll_time_tsc: AtomicU64,
+    // TSC value of the last wakeup (or spawn)
+    last_wake_tsc: AtomicU64,
+}
+impl TaskStats {
+    fn record_poll(&self, poll_time_tsc: u64) {
+        self.poll_count.fetch_add(1, Ordering::Relaxed);
+        self.poll_time_tsc
+            .fetch_add(poll_time_tsc, Ordering::Relaxed);
+    }
+    fn record_wake(&self) {
+        self.last_wake_tsc
+            .store(time::read_tsc(), Ordering::Relaxed);
+    }
 }
 
 pub struct StaticTask {
     id: TaskId, // new
+    name: String,
+    priority: TaskPriority,
+    stats: TaskStats,
     future: Mutex<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    // index of the cpu that polled it last time, wakeups are sent to its run queue
+    last_cpu: AtomicUsize,
+    // set while its id sits in a run queue, so repeated wakeups don't queue it more than once
+    queued: AtomicBool,
+    // set by the panic handler, its future stays locked forever so it's never polled again
+    panicked: AtomicBool,
+    // tells the `JoinHandle` when the task ends without finishing its future
+    completion: Option<Arc<dyn join::TaskCompletion>>,
 }
 
 impl StaticTask {
-    pub fn new(future: impl Future<Output = ()> + 'static + Send) -> StaticTask {
+    pub fn new(
+        name: impl ToString,
+        priority: TaskPriority,
+        future: impl Future<Output = ()> + 'static + Send,
+    ) -> StaticTask {
         StaticTask {
             id: TaskId::new(), // new
+            name: name.to_string(),
+            priority,
+            stats: TaskStats::default(),
             future: Mutex::new(Box::pin(future)),
+            last_cpu: AtomicUsize::new(0),
+            queued: AtomicBool::new(false),
+            panicked: AtomicBool::new(false),
+            completion: None,
         }
     }
-    fn poll(&mut self, context: &mut Context) -> core::task::Poll<()> {
-        self.future.lock().as_mut().poll(context)
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn priority(&self) -> TaskPriority {
+        self.priority
+    }
+    /// Returns `None` if the task is being polled by another cpu right now
+    fn poll(&self, context: &mut Context) -> Option<core::task::Poll<()>> {
+        let mut future = self.future.try_lock()?;
+        Some(future.as_mut().poll(context))
     }
 }
+
+/// Snapshot of a single task, made by `task_list`
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: String,
+    pub priority: TaskPriority,
+    pub poll_count: u64,
+    /// total time spent inside of `poll`
+    pub poll_time_us: u64,
+    /// how long ago it was woken (or spawned) last time
+    pub since_last_wake_us: u64,
+    /// index of the cpu that polls it right now
+    pub running_on: Option<usize>,
+}
+
+/// All tasks that are currently owned by the executors, eg. for `top` like views
+pub fn task_list() -> Vec<TaskInfo> {
+    // tasks lock is held only for copying the `Arc`s, it's shared with all executors
+    let tasks: Vec<Arc<StaticTask>> = executor::TASK_SPAWNER
+        .tasks
+        .lock()
+        .values()
+        .cloned()
+        .collect();
+    let now = time::read_tsc();
+
+    tasks
+        .iter()
+        .map(|task| TaskInfo {
+            id: task.id,
+            name: task.name.clone(),
+            priority: task.priority,
+            poll_count: task.stats.poll_count.load(Ordering::Relaxed),
+            poll_time_us: time::tsc_ticks_to_us(task.stats.poll_time_tsc.load(Ordering::Relaxed)),
+            since_last_wake_us: time::tsc_ticks_to_us(
+                now.saturating_sub(task.stats.last_wake_tsc.load(Ordering::Relaxed)),
+            ),
+            running_on: percpu::all_cpus()
+                .find(|cpu| cpu.current_task() == Some(task.id))
+                .map(|cpu| cpu.index),
+        })
+        .collect()
+}
@@ -0,0 +1,125 @@
+// `select!` and `join!` for waiting on multiple futures inside of one task.
+// Both poll their futures with the waker of the task that awaits them, so they work with any
+// executor and nothing extra gets spawned.
+
+#[doc(hidden)]
+pub mod __private {
+    pub use core::{
+        future::{Future, poll_fn},
+        pin::pin,
+        task::Poll,
+    };
+    pub use futures_util::future::maybe_done;
+}
+
+/// Waits on several futures at once and runs the handler of the first one that completes,
+/// the rest of them are dropped. Has to be used inside of an async block or function.
+///
+/// Branches are polled in order, so the earlier one wins when more of them are ready.
+/// Patterns have to be irrefutable, at most 8 branches.
+///
+/// ```ignore
+/// select! {
+///     key = keys.next() => handle_key(key),
+///     _ = time::sleep(Duration::from_secs(1)) => redraw(),
+/// }
+/// ```
+#[macro_export]
+macro_rules! select {
+    ($($pat:pat = $future:expr => $handler:expr),+ $(,)?) => {
+        $crate::select!(@munch
+            branches: [],
+            remaining: [$($pat = $future => $handler,)+],
+            variants: [A B C D E F G H],
+            indexes: [0 1 2 3 4 5 6 7]
+        )
+    };
+    (@munch
+        branches: [$($branches:tt)*],
+        remaining: [$pat:pat = $future:expr => $handler:expr, $($rest:tt)*],
+        variants: [$variant:ident $($variants:ident)*],
+        indexes: [$index:tt $($indexes:tt)*]
+    ) => {
+        $crate::select!(@munch
+            branches: [$($branches)* ($variant, $index, $pat, $future, $handler)],
+            remaining: [$($rest)*],
+            variants: [$($variants)*],
+            indexes: [$($indexes)*]
+        )
+    };
+    (@munch
+        branches: [$(($variant:ident, $index:tt, $pat:pat, $future:expr, $handler:expr))+],
+        remaining: [],
+        variants: [$($unused_variant:ident)*],
+        indexes: [$($unused_index:tt)*]
+    ) => {{
+        use $crate::task::combinators::__private::{Future, Poll, pin, poll_fn};
+
+        enum SelectOutput<$($variant),+> {
+            $($variant($variant)),+
+        }
+        // futures are dropped at the end of the block, before the handler runs
+        let output = {
+            let mut futures = ($(pin!($future),)+);
+            poll_fn(|cx| {
+                $(
+                    if let Poll::Ready(output) = futures.$index.as_mut().poll(cx) {
+                        return Poll::Ready(SelectOutput::$variant(output));
+                    }
+                )+
+                Poll::Pending
+            })
+            .await
+        };
+
+        match output {
+            $(SelectOutput::$variant($pat) => $handler,)+
+        }
+    }};
+}
+
+/// Waits until all futures complete and returns their outputs as a tuple.
+/// Has to be used inside of an async block or function, at most 8 futures.
+///
+/// ```ignore
+/// let (config, disk) = join!(load_config(), probe_disk());
+/// ```
+#[macro_export]
+macro_rules! join {
+    ($($future:expr),+ $(,)?) => {
+        $crate::join!(@munch
+            futures: [],
+            remaining: [$($future,)+],
+            indexes: [0 1 2 3 4 5 6 7]
+        )
+    };
+    (@munch
+        futures: [$($futures:tt)*],
+        remaining: [$future:expr, $($rest:tt)*],
+        indexes: [$index:tt $($indexes:tt)*]
+    ) => {
+        $crate::join!(@munch
+            futures: [$($futures)* ($index, $future)],
+            remaining: [$($rest)*],
+            indexes: [$($indexes)*]
+        )
+    };
+    (@munch
+        futures: [$(($index:tt, $future:expr))+],
+        remaining: [],
+        indexes: [$($unused_index:tt)*]
+    ) => {{
+        use $crate::task::combinators::__private::{Future, Poll, maybe_done, pin, poll_fn};
+
+        let mut futures = ($(pin!(maybe_done($future)),)+);
+        poll_fn(|cx| {
+            let mut all_done = true;
+            // finished ones keep returning `Ready` without polling the inner future again
+            $(all_done &= futures.$index.as_mut().poll(cx).is_ready();)+
+            if all_done { Poll::Ready(()) } else { Poll::Pending }
+        })
+        .await;
+
+        ($(futures.$index.as_mut().take_output().unwrap(),)+)
+    }};
+}
@@ -1,45 +1,204 @@
-use super::{StaticTask, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc};
-use core::task::Waker;
-use crossbeam_queue::ArrayQueue;
+use super::{
+    StaticTask, TaskId, TaskPriority,
+    join::{JoinError, JoinHandle, JoinState},
+};
+use crate::{
+    percpu::{self, MAX_CPUS, PerCpu},
+    sync::IrqSafeMutex,
+    thread::scheduler,
+    time,
+};
+use alloc::{boxed::Box, collections::BTreeMap, string::ToString, sync::Arc, vec::Vec};
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+    task::Waker,
+};
+use crossbeam_queue::SegQueue;
 use lazy_static::lazy_static;
-use spin::Mutex;
 
 lazy_static! {
     pub static ref TASK_SPAWNER: TaskSpawner = TaskSpawner::new();
 }
 
+const NO_TASK: u64 = u64::MAX;
+
+// task each cpu polls right now, its `Arc` is held by `run_ready_tasks`
+static POLLING: [AtomicPtr<StaticTask>; MAX_CPUS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+// task that panicked on each cpu and is still in `tasks`, removed once the locks are free
+static PANICKED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(NO_TASK) }; MAX_CPUS];
+
+/// One FIFO per priority, tasks with higher priority are popped first
+pub struct ReadyQueue {
+    queues: [SegQueue<TaskId>; TaskPriority::COUNT],
+}
+impl ReadyQueue {
+    pub const fn new() -> Self {
+        ReadyQueue {
+            queues: [const { SegQueue::new() }; TaskPriority::COUNT],
+        }
+    }
+    pub fn push(&self, task_id: TaskId, priority: TaskPriority) {
+        self.queues[priority.index()].push(task_id);
+    }
+    pub fn pop(&self) -> Option<TaskId> {
+        self.queues.iter().find_map(|queue| queue.pop())
+    }
+    pub fn pop_with_priority(&self, priority: TaskPriority) -> Option<TaskId> {
+        self.queues[priority.index()].pop()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+}
+impl Default for ReadyQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tasks are shared by all cpus, any executor can poll any of them.
 pub struct TaskSpawner {
-    pub tasks: Mutex<BTreeMap<TaskId, StaticTask>>,
-    pub task_queue: Arc<ArrayQueue<TaskId>>,
+    pub tasks: IrqSafeMutex<BTreeMap<TaskId, Arc<StaticTask>>>,
+    pub waker_cache: IrqSafeMutex<BTreeMap<TaskId, Waker>>,
+    /// newly spawned tasks, taken by whichever executor gets to them first.
+    /// Unbounded, but every task is in at most one queue at a time (see `StaticTask::queued`),
+    /// so it can't grow past the number of tasks no matter how often they are woken.
+    pub task_queue: ReadyQueue,
 }
 impl TaskSpawner {
     pub fn new() -> TaskSpawner {
         TaskSpawner {
-            tasks: Mutex::new(BTreeMap::new()),
-            task_queue: Arc::new(ArrayQueue::new(20)),
+            tasks: IrqSafeMutex::new(BTreeMap::new()),
+            waker_cache: IrqSafeMutex::new(BTreeMap::new()),
+            task_queue: ReadyQueue::new(),
         }
     }
-    pub fn spawn(&self, task: StaticTask) {
-        // to prevent any dumb shit from happening eg.(deadlocks)
-        x86_64::instructions::interrupts::disable();
+    /// Runs `future` on any cpu, await the returned handle to get its output
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_with("unnamed", TaskPriority::Interactive, future)
+    }
+
+    /// Like `spawn`, `name` and `priority` are shown by `task_list`
+    pub fn spawn_with<F>(
+        &self,
+        name: impl ToString,
+        priority: TaskPriority,
+        future: F,
+    ) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let state = Arc::new(JoinState::new());
+        let task_state = state.clone();
+        let mut task = StaticTask::new(name, priority, async move {
+            let output = future.await;
+            task_state.complete(Ok(output));
+        });
+        task.completion = Some(state.clone());
+
         let task_id = task.id;
-        if self.tasks.lock().insert(task.id, task).is_some() {
+        self.spawn_task(task);
+        JoinHandle::new(task_id, state)
+    }
+
+    fn spawn_task(&self, task: StaticTask) {
+        let (task_id, priority) = (task.id, task.priority);
+        task.queued.store(true, Ordering::Release);
+        task.stats.record_wake();
+        if self.tasks.lock().insert(task.id, Arc::new(task)).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
-        x86_64::instructions::interrupts::enable();
+        self.task_queue.push(task_id, priority);
+        wake_idle_cpu();
+    }
+
+    fn get(&self, task_id: TaskId) -> Option<Arc<StaticTask>> {
+        self.tasks.lock().get(&task_id).cloned()
+    }
+    fn waker(&self, task: &Arc<StaticTask>) -> Waker {
+        self.waker_cache
+            .lock()
+            .entry(task.id)
+            .or_insert_with(|| TaskWaker::new(Arc::downgrade(task)))
+            .clone()
+    }
+    fn remove(&self, task_id: TaskId) -> Option<Arc<StaticTask>> {
+        self.waker_cache.lock().remove(&task_id);
+        self.tasks.lock().remove(&task_id)
+    }
+
+    pub fn abort(&self, task_id: TaskId) {
+        let Some(task) = self.remove(task_id) else {
+            return;
+        };
+        if let Some(completion) = &task.completion {
+            completion.fail(JoinError::Aborted);
+        }
+        // if some cpu polls it right now, the future gets dropped with its last `Arc` instead
+        if let Some(mut future) = task.future.try_lock() {
+            *future = Box::pin(core::future::ready(()));
+        }
+    }
+
+    /// Called by the panic handler when task panicked while this cpu was polling it.
+    /// Its future is in unknown state so it's leaked instead of dropped, the `Arc` on the abandoned
+    /// stack of the executor keeps it alive. Doesn't take any lock, the panic could happen while
+    /// one is held, the task is removed from `tasks` later by `remove_panicked_task`.
+    /// WARN: locks held by the task at the moment of the panic stay locked
+    pub(crate) fn on_task_panic(&self, cpu: &PerCpu) {
+        let task = POLLING[cpu.index].swap(ptr::null_mut(), Ordering::AcqRel);
+        // SAFETY: the `Arc` in `run_ready_tasks` is never dropped, its stack was abandoned
+        let Some(task) = (unsafe { task.as_ref() }) else {
+            return;
+        };
+        task.panicked.store(true, Ordering::Release);
+        PANICKED[cpu.index].store(task.id.as_u64(), Ordering::Release);
+        if let Some(completion) = &task.completion {
+            completion.fail(JoinError::Panicked);
+        }
+    }
+
+    /// Removes the task that panicked on `cpu` if nobody holds the locks right now
+    fn remove_panicked_task(&self, cpu: &PerCpu) {
+        let task_id = PANICKED[cpu.index].load(Ordering::Acquire);
+        if task_id == NO_TASK {
+            return;
+        }
+        let (Some(mut tasks), Some(mut waker_cache)) =
+            (self.tasks.try_lock(), self.waker_cache.try_lock())
+        else {
+            return;
+        };
+        let task_id = TaskId::from_u64(task_id);
+        waker_cache.remove(&task_id);
+        tasks.remove(&task_id);
+        PANICKED[cpu.index].store(NO_TASK, Ordering::Release);
+    }
+}
+
+/// Wakes any executor that waits for tasks, so it can take them from the global queue
+fn wake_idle_cpu() {
+    if let Some(cpu) = percpu::all_cpus().find(|cpu| scheduler::is_main_thread_blocked(cpu)) {
+        scheduler::wake_main_thread(cpu);
     }
 }
 
+/// One per cpu, every online cpu runs its own executor loop in its main thread
 pub struct Executor {
-    waker_cache: BTreeMap<TaskId, Waker>,
+    cpu: &'static PerCpu,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
-            waker_cache: BTreeMap::new(),
+            cpu: percpu::this_cpu(),
         }
     }
 }
@@ -49,58 +208,126 @@ use core::task::{Context, Poll};
 impl Executor {
     pub fn run(&mut self) -> ! {
         loop {
-            // to prevent any dumb shit from happening eg.(deadlocks)
-            x86_64::instructions::interrupts::disable();
+            // polled with interrupts enabled, so the timer can switch to other threads even in
+            // the middle of a poll
+            x86_64::instructions::interrupts::enable();
             self.run_ready_tasks();
-            x86_64::instructions::interrupts::enable_and_hlt();
+            x86_64::instructions::interrupts::disable();
+
+            // wakers only wake this thread when it's blocked,
+            // so it has to be marked before the last look at the queues
+            scheduler::prepare_to_block();
+            if self.has_ready_tasks() {
+                scheduler::cancel_block();
+                continue;
+            }
+            // other threads run (or the cpu halts) until some task is woken
+            scheduler::block();
         }
     }
 
-    fn run_ready_tasks(&mut self) {
-        // destructure `self` to avoid borrow checker errors
-
-        let Self { waker_cache } = self;
+    fn has_ready_tasks(&self) -> bool {
+        !TASK_SPAWNER.task_queue.is_empty()
+            || percpu::all_cpus().any(|cpu| !cpu.run_queue.is_empty())
+    }
 
-        let mut tasks = TASK_SPAWNER.tasks.lock();
+    /// For every priority, from the highest: own run queue -> newly spawned tasks -> steal from
+    /// other cpus. So high priority task on another cpu goes before low priority one on this cpu.
+    fn next_task(&self) -> Option<TaskId> {
+        TaskPriority::ALL.into_iter().find_map(|priority| {
+            if let Some(task_id) = self.cpu.run_queue.pop_with_priority(priority) {
+                return Some(task_id);
+            }
+            if let Some(task_id) = TASK_SPAWNER.task_queue.pop_with_priority(priority) {
+                return Some(task_id);
+            }
+            percpu::all_cpus()
+                .filter(|cpu| cpu.index != self.cpu.index)
+                .find_map(|cpu| cpu.run_queue.pop_with_priority(priority))
+        })
+    }
 
-        while let Some(task_id) = TASK_SPAWNER.task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
+    fn run_ready_tasks(&mut self) {
+        TASK_SPAWNER.remove_panicked_task(self.cpu);
+        // tasks another cpu was polling, queued again only once the others had their turn
+        let mut busy = Vec::new();
+        while let Some(task_id) = self.next_task() {
+            // the tasks lock is only held for the lookup, so other cpus can poll at the same time
+            let task = match TASK_SPAWNER.get(task_id) {
                 Some(task) => task,
                 None => {
                     continue;
                 }
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, TASK_SPAWNER.task_queue.clone()));
-            let mut context = Context::from_waker(waker);
+            // queued before it panicked on another cpu that couldn't remove it yet
+            if task.panicked.load(Ordering::Acquire) {
+                continue;
+            }
+            let waker = TASK_SPAWNER.waker(&task);
+            let mut context = Context::from_waker(&waker);
 
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
+            // cleared before the poll, so wakeups that happen during it queue the task again
+            task.queued.store(false, Ordering::Release);
+
+            task.last_cpu.store(self.cpu.index, Ordering::Relaxed);
+            self.cpu.set_current_task(Some(task_id));
+            POLLING[self.cpu.index].store(Arc::as_ptr(&task).cast_mut(), Ordering::Release);
+            let poll_start = time::read_tsc();
+            let poll = task.poll(&mut context);
+            if poll.is_some() {
+                task.stats.record_poll(time::read_tsc() - poll_start);
+            }
+            POLLING[self.cpu.index].store(ptr::null_mut(), Ordering::Release);
+            self.cpu.set_current_task(None);
+
+            match poll {
+                Some(Poll::Ready(())) => {
                     // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+                    TASK_SPAWNER.remove(task_id);
+                }
+                Some(Poll::Pending) => {}
+                None => {
+                    // woken while another cpu was polling it, try again once it's done
+                    if !task.queued.swap(true, Ordering::AcqRel) {
+                        busy.push((task_id, task.priority));
+                    }
                 }
-                Poll::Pending => {}
             }
         }
+        for (task_id, priority) in busy {
+            self.cpu.run_queue.push(task_id, priority);
+        }
     }
 }
 struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    // weak, so wakers that outlive the task don't keep its future alive
+    task: alloc::sync::Weak<StaticTask>,
 }
 impl TaskWaker {
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        let Some(task) = self.task.upgrade() else {
+            return;
+        };
+        if task.panicked.load(Ordering::Acquire) {
+            return;
+        }
+        let cpu_index = task.last_cpu.load(Ordering::Relaxed);
+        let Some(cpu) = percpu::cpu(cpu_index) else {
+            return;
+        };
+        task.stats.record_wake();
+        // already waiting in some queue, waking it again changes nothing
+        if task.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        cpu.run_queue.push(task.id, task.priority);
+        // no-op unless its executor waits for tasks
+        scheduler::wake_main_thread(cpu);
     }
 }
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+    fn new(task: alloc::sync::Weak<StaticTask>) -> Waker {
+        Waker::from(Arc::new(TaskWaker { task }))
     }
 }
 use alloc::task::Wake;
@@ -114,3 +341,63 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use super::*;
+    use crate::thread::block_on;
+
+    #[test_case]
+    fn spawned_task_returns_output() {
+        let handle = TASK_SPAWNER.spawn(async { 40 + 2 });
+        assert_eq!(block_on(handle), Ok(42));
+    }
+
+    #[test_case]
+    fn tasks_run_concurrently() {
+        let handles: alloc::vec::Vec<_> = (0..8u64)
+            .map(|i| {
+                TASK_SPAWNER.spawn(async move {
+                    time::sleep(Duration::from_millis(10)).await;
+                    i
+                })
+            })
+            .collect();
+        let sum: u64 = handles
+            .into_iter()
+            .map(|handle| block_on(handle).unwrap())
+            .sum();
+        assert_eq!(sum, (0..8).sum());
+    }
+
+    #[test_case]
+    fn aborted_task_fails() {
+        let handle = TASK_SPAWNER.spawn(time::sleep(Duration::from_secs(60)));
+        handle.abort();
+        assert_eq!(block_on(handle), Err(JoinError::Aborted));
+    }
+
+    #[test_case]
+    fn panicked_task_fails() {
+        crate::testing::expect_task_panic();
+        let handle = TASK_SPAWNER.spawn(async {
+            time::sleep(Duration::from_millis(1)).await;
+            panic!("expected panic");
+        });
+        let task_id = handle.id();
+        assert_eq!(block_on(handle), Err(JoinError::Panicked));
+
+        // the executor that restarted after the panic removes it and keeps running other tasks
+        assert_eq!(block_on(TASK_SPAWNER.spawn(async { 1 })), Ok(1));
+        let deadline = time::Instant::now() + Duration::from_secs(1);
+        while TASK_SPAWNER.get(task_id).is_some() {
+            assert!(
+                time::Instant::now() < deadline,
+                "panicked task wasn't removed"
+            );
+            crate::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
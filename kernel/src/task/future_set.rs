@@ -0,0 +1,248 @@
+use alloc::{sync::Arc, task::Wake, vec::Vec};
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use crossbeam_queue::SegQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+
+use alloc::boxed::Box;
+
+/// Shared by the set and wakers of its futures
+struct Shared {
+    // indexes of slots that were woken
+    ready: SegQueue<usize>,
+    // waker of the task that polls the set
+    waker: AtomicWaker,
+}
+
+struct SlotWaker {
+    index: usize,
+    // same as `StaticTask::queued`, a slot is at most once in `ready`
+    queued: AtomicBool,
+    shared: Arc<Shared>,
+}
+impl Wake for SlotWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.shared.ready.push(self.index);
+        }
+        self.shared.waker.wake();
+    }
+}
+
+struct Slot<F> {
+    future: Pin<Box<F>>,
+    waker: Arc<SlotWaker>,
+}
+
+/// Set of futures that are driven by the task polling it and yields their outputs in
+/// the order they complete. Every future gets its own waker, so only the woken ones are polled.
+pub struct FutureSet<F: Future> {
+    slots: Vec<Option<Slot<F>>>,
+    free_slots: Vec<usize>,
+    len: usize,
+    shared: Arc<Shared>,
+}
+
+impl<F: Future> FutureSet<F> {
+    pub fn new() -> Self {
+        FutureSet {
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            len: 0,
+            shared: Arc::new(Shared {
+                ready: SegQueue::new(),
+                waker: AtomicWaker::new(),
+            }),
+        }
+    }
+
+    pub fn push(&mut self, future: F) {
+        let index = self.free_slots.pop().unwrap_or_else(|| {
+            self.slots.push(None);
+            self.slots.len() - 1
+        });
+        let waker = Arc::new(SlotWaker {
+            index,
+            queued: AtomicBool::new(false),
+            shared: self.shared.clone(),
+        });
+        self.slots[index] = Some(Slot {
+            future: Box::pin(future),
+            waker: waker.clone(),
+        });
+        self.len += 1;
+        // so it gets its first poll
+        waker.wake();
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<F: Future> Default for FutureSet<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: Future> Unpin for FutureSet<F> {}
+
+impl<F: Future> Stream for FutureSet<F> {
+    type Item = F::Output;
+
+    /// Returns `None` when the set is empty
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<F::Output>> {
+        let this = self.get_mut();
+        if this.len == 0 {
+            return Poll::Ready(None);
+        }
+        this.shared.waker.register(cx.waker());
+
+        // futures that keep waking themselves would never let it return otherwise
+        for _ in 0..this.slots.len() {
+            let Some(index) = this.shared.ready.pop() else {
+                return Poll::Pending;
+            };
+            // slot can be already empty, or reused by a different future which gets a spurious poll
+            let Some(slot) = this.slots[index].as_mut() else {
+                continue;
+            };
+            slot.waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(slot.waker.clone());
+            let mut context = Context::from_waker(&waker);
+
+            if let Poll::Ready(output) = slot.future.as_mut().poll(&mut context) {
+                this.slots[index] = None;
+                this.free_slots.push(index);
+                this.len -= 1;
+                return Poll::Ready(Some(output));
+            }
+        }
+        // let other tasks run before going through the rest of them
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{sync::atomic::AtomicUsize, time::Duration};
+
+    use futures_util::StreamExt;
+
+    use super::*;
+    use crate::{thread::block_on, time};
+
+    async fn after(ms: u64, value: u32) -> u32 {
+        time::sleep(Duration::from_millis(ms)).await;
+        value
+    }
+
+    /// Completes once `ready` is set, counts its polls
+    #[derive(Default)]
+    struct Manual {
+        polls: AtomicUsize,
+        ready: AtomicBool,
+        waker: AtomicWaker,
+    }
+    impl Manual {
+        fn polls(&self) -> usize {
+            self.polls.load(Ordering::Relaxed)
+        }
+        fn complete(&self) {
+            self.ready.store(true, Ordering::Release);
+            self.waker.wake();
+        }
+    }
+    struct ManualFuture(Arc<Manual>, u32);
+    impl Future for ManualFuture {
+        type Output = u32;
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<u32> {
+            self.0.polls.fetch_add(1, Ordering::Relaxed);
+            self.0.waker.register(cx.waker());
+            match self.0.ready.load(Ordering::Acquire) {
+                true => Poll::Ready(self.1),
+                false => Poll::Pending,
+            }
+        }
+    }
+
+    fn poll_set(set: &mut FutureSet<ManualFuture>) -> Poll<Option<u32>> {
+        Pin::new(set).poll_next(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test_case]
+    fn outputs_in_completion_order() {
+        let mut set = FutureSet::new();
+        set.push(after(30, 3));
+        set.push(after(10, 1));
+        set.push(after(20, 2));
+        let outputs: Vec<u32> = block_on(set.collect());
+        assert_eq!(outputs, [1, 2, 3]);
+    }
+
+    #[test_case]
+    fn polls_only_woken_futures() {
+        let (a, b) = (Arc::new(Manual::default()), Arc::new(Manual::default()));
+        let mut set = FutureSet::new();
+        set.push(ManualFuture(a.clone(), 1));
+        set.push(ManualFuture(b.clone(), 2));
+        assert_eq!(poll_set(&mut set), Poll::Pending);
+        assert_eq!((a.polls(), b.polls()), (1, 1));
+
+        b.complete();
+        assert_eq!(poll_set(&mut set), Poll::Ready(Some(2)));
+        assert_eq!(poll_set(&mut set), Poll::Pending);
+        assert_eq!((a.polls(), b.polls()), (1, 2));
+
+        a.waker.wake();
+        assert_eq!(poll_set(&mut set), Poll::Pending);
+        assert_eq!(a.polls(), 2);
+        a.complete();
+        assert_eq!(poll_set(&mut set), Poll::Ready(Some(1)));
+        assert_eq!(poll_set(&mut set), Poll::Ready(None));
+    }
+
+    #[test_case]
+    fn reuses_free_slots() {
+        let (a, b, c) = (
+            Arc::new(Manual::default()),
+            Arc::new(Manual::default()),
+            Arc::new(Manual::default()),
+        );
+        let mut set = FutureSet::new();
+        set.push(ManualFuture(a.clone(), 1));
+        set.push(ManualFuture(b.clone(), 2));
+        assert_eq!(poll_set(&mut set), Poll::Pending);
+        // keeps the waker of the finished future, like a timer that fires late
+        let stale_waker = b.waker.take().unwrap();
+        b.ready.store(true, Ordering::Release);
+        stale_waker.wake_by_ref();
+        assert_eq!(poll_set(&mut set), Poll::Ready(Some(2)));
+
+        set.push(ManualFuture(c.clone(), 3));
+        assert_eq!(set.slots.len(), 2);
+        assert_eq!(set.len(), 2);
+        assert_eq!(poll_set(&mut set), Poll::Pending);
+
+        // stale wakeup only gives the new future of the slot a spurious poll
+        stale_waker.wake();
+        assert_eq!(poll_set(&mut set), Poll::Pending);
+        assert_eq!((a.polls(), c.polls()), (1, 2));
+        c.complete();
+        assert_eq!(poll_set(&mut set), Poll::Ready(Some(3)));
+        assert_eq!(set.len(), 1);
+    }
+}
@@ -0,0 +1,98 @@
+use alloc::sync::Arc;
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+
+use super::{TaskId, executor::TASK_SPAWNER};
+use crate::sync::IrqSafeMutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// `JoinHandle::abort` was called before the task finished
+    Aborted,
+    /// task panicked while being polled, its future was leaked
+    Panicked,
+}
+
+/// Shared by the task and its `JoinHandle`
+pub(crate) struct JoinState<T> {
+    result: IrqSafeMutex<Option<Result<T, JoinError>>>,
+    // stays true after the result is taken by the handle
+    finished: AtomicBool,
+    waker: AtomicWaker,
+}
+impl<T> JoinState<T> {
+    pub(crate) fn new() -> Self {
+        JoinState {
+            result: IrqSafeMutex::new(None),
+            finished: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+    /// only the first result is kept (eg. task can't finish after it was aborted)
+    pub(crate) fn complete(&self, result: Result<T, JoinError>) {
+        let mut current = self.result.lock();
+        if !self.finished.swap(true, Ordering::AcqRel) {
+            *current = Some(result);
+        }
+        drop(current);
+        self.waker.wake();
+    }
+}
+
+/// Lets the executor fail a task without knowing its output type
+pub(crate) trait TaskCompletion: Send + Sync {
+    fn fail(&self, error: JoinError);
+}
+impl<T: Send> TaskCompletion for JoinState<T> {
+    fn fail(&self, error: JoinError) {
+        self.complete(Err(error));
+    }
+}
+
+/// Returned by `TaskSpawner::spawn`, await it to get the output of the task.
+/// Dropping it detaches the task, it keeps running.
+pub struct JoinHandle<T> {
+    task_id: TaskId,
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(crate) fn new(task_id: TaskId, state: Arc<JoinState<T>>) -> Self {
+        JoinHandle { task_id, state }
+    }
+    pub fn id(&self) -> TaskId {
+        self.task_id
+    }
+    /// Drops the future of the task (or leaves it for the cpu that polls it right now) and
+    /// removes the task from the executor. Awaiting the handle returns `JoinError::Aborted`.
+    pub fn abort(&self) {
+        TASK_SPAWNER.abort(self.task_id);
+    }
+    pub fn is_finished(&self) -> bool {
+        self.state.finished.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // fast path
+        if let Some(result) = self.state.result.lock().take() {
+            return Poll::Ready(result);
+        }
+
+        self.state.waker.register(cx.waker());
+        match self.state.result.lock().take() {
+            Some(result) => {
+                self.state.waker.take();
+                Poll::Ready(result)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
@@ -1,11 +1,11 @@
-use alloc::{string::String, vec::Vec};
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 
 use log::*;
-use spin::Mutex;
+
+use crate::sync::Listeners;
 /// Called by the keyboard interrupt handler
 ///
 /// Must not block or allocate.
@@ -83,9 +83,7 @@ pub(crate) async fn print_keypresses() {
             if let Some(key) = keyboard.process_keyevent(key_event) {
                 //debug!("key pressed:{key:?}");
                 // call all listeners
-                for listener in ON_KEY_PRESSED_LISTENERS.lock().iter() {
-                    listener(&key);
-                }
+                ON_KEY_PRESSED_LISTENERS.call_each(|listener| listener(&key));
 
 
             }
@@ -94,4 +92,4 @@ pub(crate) async fn print_keypresses() {
 
 }
 pub type OnKeyFunction = fn(&DecodedKey);
-pub static ON_KEY_PRESSED_LISTENERS: Mutex<Vec<OnKeyFunction>> = Mutex::new(Vec::new());
+pub static ON_KEY_PRESSED_LISTENERS: Listeners<OnKeyFunction> = Listeners::new();
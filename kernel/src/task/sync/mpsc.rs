@@ -0,0 +1,187 @@
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+
+use super::Semaphore;
+
+/// Receiver is gone, the value is given back
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// every sender was dropped and the channel is empty
+    Disconnected,
+}
+
+struct Shared<T> {
+    queue: ArrayQueue<T>,
+    // one permit for every free slot in `queue`, senders wait on it while the channel is full.
+    // Closed when the receiver is dropped.
+    free_slots: Semaphore,
+    senders: AtomicUsize,
+    receiver_waker: AtomicWaker,
+}
+
+/// Multi-producer single-consumer channel that holds at most `capacity` values,
+/// `Sender::send` waits when it's full.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity has to be at least 1");
+    let shared = Arc::new(Shared {
+        queue: ArrayQueue::new(capacity),
+        free_slots: Semaphore::new(capacity),
+        senders: AtomicUsize::new(1),
+        receiver_waker: AtomicWaker::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Waits for a free slot
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        match self.shared.free_slots.acquire().await {
+            Ok(permit) => permit.forget(),
+            Err(_) => return Err(SendError(value)),
+        }
+        self.push(value);
+        Ok(())
+    }
+
+    /// Doesn't block or allocate, so it can be used from interrupt handlers
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        match self.shared.free_slots.try_acquire() {
+            Ok(permit) => permit.forget(),
+            Err(super::TryAcquireError::Closed) => return Err(TrySendError::Closed(value)),
+            Err(super::TryAcquireError::NoPermits) => return Err(TrySendError::Full(value)),
+        }
+        self.push(value);
+        Ok(())
+    }
+
+    fn push(&self, value: T) {
+        // slot was reserved by taking a permit
+        if self.shared.queue.push(value).is_err() {
+            unreachable!("channel queue full even though a slot was reserved");
+        }
+        self.shared.receiver_waker.wake();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.free_slots.is_closed()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // last one, let the receiver see that the channel is closed
+            self.shared.receiver_waker.wake();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Returns `None` once every sender is dropped and the channel is empty
+    pub async fn recv(&mut self) -> Option<T> {
+        core::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.pop() {
+            return Ok(value);
+        }
+        if self.shared.senders.load(Ordering::Acquire) == 0 {
+            // sender could push right before it was dropped
+            return self.pop().ok_or(TryRecvError::Disconnected);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        // fast path
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Some(value)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        self.shared.receiver_waker.register(cx.waker());
+        match self.try_recv() {
+            Ok(value) => {
+                self.shared.receiver_waker.take();
+                Poll::Ready(Some(value))
+            }
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        let value = self.shared.queue.pop()?;
+        self.shared.free_slots.add_permits(1);
+        Some(value)
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // wakes senders waiting for a free slot, values still in the queue are dropped with it
+        self.shared.free_slots.close();
+    }
+}
@@ -0,0 +1,135 @@
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+
+use crate::sync::IrqSafeMutex;
+
+const WAITING: u8 = 0;
+const NOTIFIED_ONE: u8 = 1;
+const NOTIFIED_ALL: u8 = 2;
+
+struct Waiter {
+    // only changed while the state lock is held
+    state: AtomicU8,
+    waker: AtomicWaker,
+}
+
+struct State {
+    // `notify_one` that came when nobody was waiting, taken by the next `notified`
+    permit: bool,
+    waiters: VecDeque<Arc<Waiter>>,
+}
+
+/// Wakes tasks waiting for some event, without passing any data.
+/// Safe to notify from interrupt handlers.
+pub struct Notify {
+    state: IrqSafeMutex<State>,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: IrqSafeMutex::new(State {
+                permit: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Wakes the task that waits the longest, or if nobody waits the next one that calls `notified`
+    pub fn notify_one(&self) {
+        self.state.lock().notify_one();
+    }
+
+    /// Wakes every task that waits right now.
+    /// WARN: only reaches `Notified` futures that were already polled
+    pub fn notify_waiters(&self) {
+        let mut state = self.state.lock();
+        for waiter in state.waiters.drain(..) {
+            waiter.state.store(NOTIFIED_ALL, Ordering::Release);
+            waiter.waker.wake();
+        }
+    }
+
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            waiter: None,
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    fn notify_one(&mut self) {
+        match self.waiters.pop_front() {
+            Some(waiter) => {
+                waiter.state.store(NOTIFIED_ONE, Ordering::Release);
+                waiter.waker.wake();
+            }
+            None => self.permit = true,
+        }
+    }
+}
+
+/// Future returned by `Notify::notified`
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    // `Some` while queued
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        let Some(waiter) = &this.waiter else {
+            // first poll
+            let mut state = this.notify.state.lock();
+            if state.permit {
+                state.permit = false;
+                return Poll::Ready(());
+            }
+            let waiter = Arc::new(Waiter {
+                state: AtomicU8::new(WAITING),
+                waker: AtomicWaker::new(),
+            });
+            waiter.waker.register(cx.waker());
+            state.waiters.push_back(waiter.clone());
+            this.waiter = Some(waiter);
+            return Poll::Pending;
+        };
+
+        waiter.waker.register(cx.waker());
+        if waiter.state.load(Ordering::Acquire) == WAITING {
+            return Poll::Pending;
+        }
+        this.waiter = None;
+        Poll::Ready(())
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let Some(waiter) = self.waiter.take() else {
+            return;
+        };
+        let mut state = self.notify.state.lock();
+        match waiter.state.load(Ordering::Acquire) {
+            WAITING => state.waiters.retain(|queued| !Arc::ptr_eq(queued, &waiter)),
+            // `notify_one` picked this one but it will never see it, so pass it on
+            NOTIFIED_ONE => state.notify_one(),
+            _ => {}
+        }
+    }
+}
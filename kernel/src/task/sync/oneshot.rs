@@ -0,0 +1,112 @@
+use alloc::sync::Arc;
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+
+use crate::sync::IrqSafeMutex;
+
+/// Sender was dropped without sending a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+}
+
+struct Shared<T> {
+    value: IrqSafeMutex<Option<T>>,
+    // value was sent or sender was dropped
+    complete: AtomicBool,
+    receiver_dropped: AtomicBool,
+    waker: AtomicWaker,
+}
+
+/// Channel for sending a single value, eg. a reply to a request
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        value: IrqSafeMutex::new(None),
+        complete: AtomicBool::new(false),
+        receiver_dropped: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Doesn't block, so it can be used from interrupt handlers.
+    /// Returns the value back if the receiver is gone.
+    pub fn send(self, value: T) -> Result<(), T> {
+        if self.shared.receiver_dropped.load(Ordering::Acquire) {
+            return Err(value);
+        }
+        *self.shared.value.lock() = Some(value);
+        self.shared.complete.store(true, Ordering::Release);
+        self.shared.waker.wake();
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.receiver_dropped.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // no-op after `send`
+        if !self.shared.complete.swap(true, Ordering::AcqRel) {
+            self.shared.waker.wake();
+        }
+    }
+}
+
+/// Await it to get the value
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if !self.shared.complete.load(Ordering::Acquire) {
+            return Err(TryRecvError::Empty);
+        }
+        self.shared.value.lock().take().ok_or(TryRecvError::Closed)
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // fast path
+        if self.shared.complete.load(Ordering::Acquire) {
+            return Poll::Ready(self.shared.value.lock().take().ok_or(RecvError));
+        }
+
+        self.shared.waker.register(cx.waker());
+        if self.shared.complete.load(Ordering::Acquire) {
+            self.shared.waker.take();
+            return Poll::Ready(self.shared.value.lock().take().ok_or(RecvError));
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_dropped.store(true, Ordering::Release);
+    }
+}
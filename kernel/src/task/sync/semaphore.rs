@@ -0,0 +1,216 @@
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+
+use crate::sync::IrqSafeMutex;
+
+/// Semaphore was closed before the permits could be acquired
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcquireError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryAcquireError {
+    Closed,
+    NoPermits,
+}
+
+const WAITING: u8 = 0;
+const ASSIGNED: u8 = 1;
+const CLOSED: u8 = 2;
+
+struct Waiter {
+    needed: usize,
+    // only changed while the state lock is held
+    state: AtomicU8,
+    waker: AtomicWaker,
+}
+
+struct State {
+    permits: usize,
+    closed: bool,
+    // FIFO, so big requests (eg. `RwLock::write`) aren't starved by a stream of small ones
+    waiters: VecDeque<Arc<Waiter>>,
+}
+impl State {
+    /// Hands out permits to the waiters from the front of the queue while there are enough of them
+    fn assign_permits(&mut self) {
+        while let Some(waiter) = self.waiters.front() {
+            if waiter.needed > self.permits {
+                break;
+            }
+            self.permits -= waiter.needed;
+            waiter.state.store(ASSIGNED, Ordering::Release);
+            waiter.waker.wake();
+            self.waiters.pop_front();
+        }
+    }
+}
+
+/// Async counting semaphore, waiting tasks get their permits in the order they asked for them.
+/// Safe to release permits from interrupt handlers.
+pub struct Semaphore {
+    state: IrqSafeMutex<State>,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: IrqSafeMutex::new(State {
+                permits,
+                closed: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    pub fn add_permits(&self, count: usize) {
+        let mut state = self.state.lock();
+        state.permits += count;
+        state.assign_permits();
+    }
+
+    /// Every waiting and future `acquire` returns `AcquireError`, permits that are held stay valid
+    pub fn close(&self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        for waiter in state.waiters.drain(..) {
+            waiter.state.store(CLOSED, Ordering::Release);
+            waiter.waker.wake();
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    /// Fails if anybody is already waiting, even if there are enough permits for this call
+    pub fn try_acquire_many(&self, count: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        let mut state = self.state.lock();
+        if state.closed {
+            return Err(TryAcquireError::Closed);
+        }
+        if !state.waiters.is_empty() || state.permits < count {
+            return Err(TryAcquireError::NoPermits);
+        }
+        state.permits -= count;
+        Ok(SemaphorePermit::new(self, count))
+    }
+
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    pub fn acquire_many(&self, count: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            needed: count,
+            waiter: None,
+        }
+    }
+}
+
+/// Future returned by `Semaphore::acquire`.
+/// Dropping it gives back the permits it already got and leaves the queue.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    needed: usize,
+    // `Some` while queued
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Result<SemaphorePermit<'a>, AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let Some(waiter) = &this.waiter else {
+            // first poll
+            let mut state = this.semaphore.state.lock();
+            if state.closed {
+                return Poll::Ready(Err(AcquireError));
+            }
+            if state.waiters.is_empty() && state.permits >= this.needed {
+                state.permits -= this.needed;
+                return Poll::Ready(Ok(SemaphorePermit::new(this.semaphore, this.needed)));
+            }
+            let waiter = Arc::new(Waiter {
+                needed: this.needed,
+                state: AtomicU8::new(WAITING),
+                waker: AtomicWaker::new(),
+            });
+            waiter.waker.register(cx.waker());
+            state.waiters.push_back(waiter.clone());
+            this.waiter = Some(waiter);
+            return Poll::Pending;
+        };
+
+        waiter.waker.register(cx.waker());
+        match waiter.state.load(Ordering::Acquire) {
+            WAITING => Poll::Pending,
+            ASSIGNED => {
+                this.waiter = None;
+                Poll::Ready(Ok(SemaphorePermit::new(this.semaphore, this.needed)))
+            }
+            _ => {
+                this.waiter = None;
+                Poll::Ready(Err(AcquireError))
+            }
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let Some(waiter) = self.waiter.take() else {
+            return;
+        };
+        let mut state = self.semaphore.state.lock();
+        match waiter.state.load(Ordering::Acquire) {
+            WAITING => state.waiters.retain(|queued| !Arc::ptr_eq(queued, &waiter)),
+            // got permits but was never polled again to take them
+            ASSIGNED => state.permits += waiter.needed,
+            _ => return,
+        }
+        // waiters behind this one might fit now
+        state.assign_permits();
+    }
+}
+
+/// Gives its permits back to the semaphore when dropped
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl<'a> SemaphorePermit<'a> {
+    fn new(semaphore: &'a Semaphore, permits: usize) -> Self {
+        SemaphorePermit { semaphore, permits }
+    }
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+    /// Keeps the permits taken, they have to be returned with `Semaphore::add_permits`
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
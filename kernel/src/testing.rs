@@ -0,0 +1,122 @@
+//! Test framework for `#[test_case]` tests, they run inside of QEMU.
+//!
+//! `cargo test` in `kernel/` builds a test kernel for the lib and for every file in `kernel/tests/`,
+//! the runner from `.cargo/config.toml` boots each of them. Results are printed to serial and
+//! QEMU exits through isa-debug-exit, with `QemuExitCode::Success` once every test passed.
+//! Integration tests set `kernel::testing::test_runner` as their runner and call `run` from
+//! their entry point.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{
+    hlt_loop,
+    qemu::{QemuExitCode, exit_qemu},
+    task::{TaskPriority, executor::TASK_SPAWNER},
+    thread, time,
+};
+
+/// A test that takes longer fails, so a deadlock doesn't hang the whole run
+const TEST_TIMEOUT_MS: u64 = 10_000;
+
+const NO_TEST: u64 = u64::MAX;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// `time::TIME_MS` when the current test started
+static TEST_STARTED_MS: AtomicU64 = AtomicU64::new(NO_TEST);
+// task panics that don't fail the test run, set by `expect_task_panic`
+static EXPECTED_PANICS: AtomicUsize = AtomicUsize::new(0);
+
+pub trait Testable {
+    fn run(&self);
+}
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        print(format_args!("{}...\t", core::any::type_name::<T>()));
+        TEST_STARTED_MS.store(time::TIME_MS.load(Ordering::Relaxed), Ordering::Relaxed);
+        self();
+        TEST_STARTED_MS.store(NO_TEST, Ordering::Relaxed);
+        print(format_args!("[ok]\n"));
+    }
+}
+
+/// Set as `#![test_runner]`, exits QEMU once every test passed
+pub fn test_runner(tests: &[&dyn Testable]) {
+    print(format_args!("running {} tests\n", tests.len()));
+    for test in tests {
+        test.run();
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Boots the kernel and runs `test_main` (generated by `reexport_test_harness_main`) on a kernel
+/// thread, so tests can block on async code while the executors keep running
+pub fn run(boot_info: &'static mut bootloader_api::BootInfo, test_main: fn()) -> ! {
+    RUNNING.store(true, Ordering::Release);
+    crate::init_kernel(boot_info);
+
+    TASK_SPAWNER.spawn_with("test watchdog", TaskPriority::Interactive, watchdog());
+    // threads can be spawned only once cpus have main threads, so from inside of a task
+    TASK_SPAWNER.spawn_with("tests", TaskPriority::Interactive, async move {
+        thread::spawn_with("tests", thread::ThreadPriority::Normal, test_main);
+    });
+    crate::start_task_executor_loop();
+}
+
+/// Whether the kernel runs tests, panics fail the test run instead of only killing a task
+/// (unless `expect_task_panic` was called)
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Acquire)
+}
+
+/// Lets the next panic inside of a task kill only the task (like outside of tests), for tests
+/// of what happens after a task panics. Panics outside of tasks still fail the test run.
+pub fn expect_task_panic() {
+    EXPECTED_PANICS.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Called by the panic handler for panics inside of tasks, true if one was expected
+pub fn take_expected_panic() -> bool {
+    EXPECTED_PANICS
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |expected| {
+            expected.checked_sub(1)
+        })
+        .is_ok()
+}
+
+/// Called by the panic handler while tests run
+pub fn fail(info: &core::panic::PanicInfo) -> ! {
+    // the panicking code could hold the logger's serial port
+    let mut serial = unsafe { crate::serial::SerialPort::emergency() };
+    let _ = write!(serial, "[failed]\n\nError: {info}\n");
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
+async fn watchdog() {
+    let mut interval = time::interval(core::time::Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let started = TEST_STARTED_MS.load(Ordering::Relaxed);
+        if started != NO_TEST
+            && time::TIME_MS
+                .load(Ordering::Relaxed)
+                .saturating_sub(started)
+                > TEST_TIMEOUT_MS
+        {
+            print(format_args!(
+                "[timeout]\n\nError: test took over {TEST_TIMEOUT_MS} ms\n"
+            ));
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+}
+
+/// Straight to serial, test results shouldn't be filtered or formatted like logs
+fn print(args: fmt::Arguments) {
+    crate::logger::with_serial_port(|serial| {
+        let _ = serial.write_fmt(args);
+    });
+}
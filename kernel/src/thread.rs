@@ -0,0 +1,317 @@
+mod condvar;
+mod context;
+mod mutex;
+pub(crate) mod scheduler;
+mod wait_queue;
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    task::Wake,
+    vec,
+};
+use core::{
+    cell::UnsafeCell,
+    pin::pin,
+    sync::atomic::{AtomicU8, AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use crate::{percpu, sync::IrqSafeMutex, task::TaskId, time};
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use wait_queue::WaitQueue;
+
+// Kernel threads, not to be confused with `threads` which starts the cpus.
+// Every cpu runs its own set of threads, the code that booted the cpu (and later runs its async
+// executor) becomes its main thread. Threads never move to a different cpu.
+
+pub const STACK_SIZE: usize = 64 * 1024;
+// `saved_task` value when the thread wasn't polling any task
+const NO_TASK: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// Ready thread with higher priority always runs first, threads with the same priority take turns.
+/// Executors run as `Normal` threads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ThreadPriority {
+    High,
+    Normal,
+    Low,
+}
+impl ThreadPriority {
+    pub const COUNT: usize = 3;
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Blocked,
+    Finished,
+}
+impl ThreadState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => ThreadState::Ready,
+            1 => ThreadState::Running,
+            2 => ThreadState::Blocked,
+            _ => ThreadState::Finished,
+        }
+    }
+}
+
+type ThreadEntry = Box<dyn FnOnce() + Send>;
+
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    priority: ThreadPriority,
+    cpu: usize,
+    state: AtomicU8,
+    // saved by `context::switch_context` while the thread is switched out
+    stack_pointer: UnsafeCell<u64>,
+    // `None` for main threads, they run on the stack the cpu booted with
+    stack: Option<Box<[u8]>>,
+    entry: IrqSafeMutex<Option<ThreadEntry>>,
+    // threads waiting in `JoinHandle::join`
+    exit_waiters: WaitQueue,
+    // per-cpu values that belong to the thread, swapped on every switch
+    saved_interrupt_depth: AtomicUsize,
+    saved_task: AtomicU64,
+}
+// `stack_pointer` is only touched by the cpu that runs the thread, with interrupts disabled
+unsafe impl Sync for Thread {}
+unsafe impl Send for Thread {}
+
+impl Thread {
+    fn new(
+        name: String,
+        priority: ThreadPriority,
+        cpu: usize,
+        stack: Option<Box<[u8]>>,
+        state: ThreadState,
+    ) -> Self {
+        Thread {
+            id: ThreadId::new(),
+            name,
+            priority,
+            cpu,
+            state: AtomicU8::new(state as u8),
+            stack_pointer: UnsafeCell::new(0),
+            stack,
+            entry: IrqSafeMutex::new(None),
+            exit_waiters: WaitQueue::new(),
+            saved_interrupt_depth: AtomicUsize::new(0),
+            saved_task: AtomicU64::new(NO_TASK),
+        }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn priority(&self) -> ThreadPriority {
+        self.priority
+    }
+    /// index of the cpu it runs on
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+    pub fn state(&self) -> ThreadState {
+        ThreadState::from_u8(self.state.load(Ordering::Acquire))
+    }
+    fn set_state(&self, state: ThreadState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+    /// true for the thread that booted its cpu
+    pub fn is_main(&self) -> bool {
+        self.stack.is_none()
+    }
+
+    fn save_cpu_state(&self, cpu: &percpu::PerCpu) {
+        self.saved_interrupt_depth
+            .store(cpu.interrupt_depth(), Ordering::Relaxed);
+        let task = cpu.current_task().map_or(NO_TASK, TaskId::as_u64);
+        self.saved_task.store(task, Ordering::Relaxed);
+    }
+    fn restore_cpu_state(&self, cpu: &percpu::PerCpu) {
+        cpu.set_interrupt_depth(self.saved_interrupt_depth.load(Ordering::Relaxed));
+        let task = match self.saved_task.load(Ordering::Relaxed) {
+            NO_TASK => None,
+            id => Some(TaskId::from_u64(id)),
+        };
+        cpu.set_current_task(task);
+    }
+}
+
+/// Turns the code that runs on this cpu into its main thread.
+/// Has to be called once by every cpu before it starts its executor.
+pub fn init_cpu() {
+    let cpu = percpu::this_cpu();
+    let name = alloc::format!("main {}", cpu.index);
+    let thread = Thread::new(
+        name,
+        ThreadPriority::Normal,
+        cpu.index,
+        None,
+        ThreadState::Running,
+    );
+    scheduler::init_cpu(cpu, Arc::new(thread));
+}
+
+/// Starts `entry` on a new thread
+pub fn spawn<T: Send + 'static>(entry: impl FnOnce() -> T + Send + 'static) -> JoinHandle<T> {
+    spawn_with("unnamed", ThreadPriority::Normal, entry)
+}
+
+/// Like `spawn`, threads are spread over the cpus in round-robin order
+pub fn spawn_with<T: Send + 'static>(
+    name: impl ToString,
+    priority: ThreadPriority,
+    entry: impl FnOnce() -> T + Send + 'static,
+) -> JoinHandle<T> {
+    let cpu = scheduler::next_cpu();
+    let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+    let stack_pointer = context::initial_stack_pointer(&mut stack, thread_entry);
+
+    let thread = Thread::new(
+        name.to_string(),
+        priority,
+        cpu,
+        Some(stack),
+        ThreadState::Ready,
+    );
+    unsafe { *thread.stack_pointer.get() = stack_pointer };
+
+    let result = Arc::new(IrqSafeMutex::new(None));
+    let thread_result = result.clone();
+    *thread.entry.lock() = Some(Box::new(move || {
+        let output = entry();
+        *thread_result.lock() = Some(output);
+    }));
+
+    let thread = Arc::new(thread);
+    scheduler::add(thread.clone());
+    JoinHandle { thread, result }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// thread panicked, so it never returned
+    Panicked,
+}
+
+/// Returned by `spawn`, dropping it detaches the thread
+pub struct JoinHandle<T> {
+    thread: Arc<Thread>,
+    result: Arc<IrqSafeMutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Arc<Thread> {
+        &self.thread
+    }
+    pub fn is_finished(&self) -> bool {
+        self.thread.state() == ThreadState::Finished
+    }
+    /// Blocks the current thread until this one finishes and returns what it returned
+    pub fn join(self) -> Result<T, JoinError> {
+        self.thread
+            .exit_waiters
+            .wait_until(|| self.thread.state() == ThreadState::Finished);
+        self.result.lock().take().ok_or(JoinError::Panicked)
+    }
+}
+
+/// First thing every new thread runs, `switch_context` returns here
+extern "C" fn thread_entry() -> ! {
+    scheduler::after_switch();
+    x86_64::instructions::interrupts::enable();
+
+    let thread = current().expect("thread started on a cpu without threads");
+    let entry = thread.entry.lock().take();
+    drop(thread);
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+/// Thread that runs on this cpu, `None` before `init_cpu`
+pub fn current() -> Option<Arc<Thread>> {
+    scheduler::current(percpu::try_this_cpu()?)
+}
+
+/// Lets other ready threads with the same or higher priority run
+pub fn yield_now() {
+    scheduler::yield_now();
+}
+
+/// Ends the current thread
+pub fn exit() -> ! {
+    scheduler::exit();
+}
+
+struct ThreadWaker {
+    thread: Arc<Thread>,
+}
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        scheduler::wake(&self.thread);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        scheduler::wake(&self.thread);
+    }
+}
+
+/// Runs `future` to completion on the current thread, which is blocked while it's pending.
+/// Lets blocking code use async APIs (eg. timers, channels).
+/// WARN: calling it from a main thread stops its executor until the future completes
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let thread = current().expect("threads are not initialized on this cpu");
+    let waker = Waker::from(Arc::new(ThreadWaker { thread }));
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+
+    loop {
+        // before the poll, so a wakeup that comes during it isn't lost
+        scheduler::prepare_to_block();
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            scheduler::cancel_block();
+            return output;
+        }
+        scheduler::block();
+    }
+}
+
+/// Blocks the current thread, uses the same timers as `time::sleep`
+pub fn sleep(duration: Duration) {
+    block_on(time::sleep(duration));
+}
+
+pub fn sleep_ms(length_ms: u64) {
+    sleep(Duration::from_millis(length_ms));
+}
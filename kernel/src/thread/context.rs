@@ -0,0 +1,51 @@
+// callee saved registers + rflags, pushed by `switch_context`
+const SAVED_REGISTERS: usize = 7;
+
+/// Saves callee saved registers and flags of the current thread on its stack, stores its stack
+/// pointer to `old_stack_pointer` and continues the thread that was saved with `new_stack_pointer`.
+/// Returns once some other thread switches back to this one.
+///
+/// # Safety
+/// Interrupts have to be disabled and `new_stack_pointer` has to come from `switch_context`
+/// or `initial_stack_pointer`.
+#[unsafe(naked)]
+pub(super) unsafe extern "C" fn switch_context(
+    old_stack_pointer: *mut u64,
+    new_stack_pointer: u64,
+) {
+    core::arch::naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "pushfq",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "popfq",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
+
+/// Fills the top of a new thread's stack the same way `switch_context` leaves it,
+/// so switching to it "returns" into `entry`
+pub(super) fn initial_stack_pointer(stack: &mut [u8], entry: extern "C" fn() -> !) -> u64 {
+    let top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xF;
+    let mut frame = [0u64; SAVED_REGISTERS + 2];
+    // interrupts disabled, bit 1 is always set
+    frame[0] = 0x2;
+    frame[SAVED_REGISTERS] = entry as usize as u64;
+    // fake return address of `entry`, so the stack is aligned as if it was called
+    frame[SAVED_REGISTERS + 1] = 0;
+
+    let stack_pointer = top - size_of_val(&frame) as u64;
+    unsafe { (stack_pointer as *mut [u64; SAVED_REGISTERS + 2]).write(frame) };
+    stack_pointer
+}
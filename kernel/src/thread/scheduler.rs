@@ -0,0 +1,264 @@
+use alloc::{collections::VecDeque, sync::Arc};
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+use super::{Thread, ThreadPriority, ThreadState, context};
+use crate::{
+    interrupts::ipi::{self, IpiTarget},
+    percpu::{self, PerCpu},
+    sync::IrqSafeMutex,
+};
+
+/// How many LAPIC timer ticks a thread runs before others with the same priority get a turn
+const TIME_SLICE_TICKS: u32 = 10;
+
+/// Threads of a single cpu
+pub(crate) struct Scheduler {
+    // one FIFO per priority
+    ready: IrqSafeMutex<[VecDeque<Arc<Thread>>; ThreadPriority::COUNT]>,
+    current: IrqSafeMutex<Option<Arc<Thread>>>,
+    // thread that was just switched out, released by the next one once it's off its stack
+    previous: IrqSafeMutex<Option<Arc<Thread>>>,
+    main_thread: OnceCell<Arc<Thread>>,
+    ticks_left: AtomicU32,
+}
+
+impl Scheduler {
+    pub(crate) fn new() -> Self {
+        Scheduler {
+            ready: IrqSafeMutex::new([const { VecDeque::new() }; ThreadPriority::COUNT]),
+            current: IrqSafeMutex::new(None),
+            previous: IrqSafeMutex::new(None),
+            main_thread: OnceCell::uninit(),
+            ticks_left: AtomicU32::new(TIME_SLICE_TICKS),
+        }
+    }
+
+    fn has_ready(&self) -> bool {
+        self.ready.lock().iter().any(|queue| !queue.is_empty())
+    }
+
+    /// Highest priority ready thread, but only if it's at least as important as `min_priority`
+    fn pop_ready(&self, min_priority: ThreadPriority) -> Option<Arc<Thread>> {
+        let mut ready = self.ready.lock();
+        ready[..=min_priority.index()]
+            .iter_mut()
+            .find_map(|queue| queue.pop_front())
+    }
+}
+
+pub(super) fn init_cpu(cpu: &PerCpu, main_thread: Arc<Thread>) {
+    *cpu.scheduler.current.lock() = Some(main_thread.clone());
+    cpu.scheduler
+        .main_thread
+        .try_init_once(|| main_thread)
+        .expect("threads were already initialized on this cpu");
+}
+
+pub(super) fn current(cpu: &PerCpu) -> Option<Arc<Thread>> {
+    cpu.scheduler.current.lock().clone()
+}
+
+/// Cpus that run threads are picked in turns
+pub(super) fn next_cpu() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    let cpus = || percpu::all_cpus().filter(|cpu| cpu.scheduler.main_thread.is_initialized());
+    let count = cpus().count();
+    assert!(count > 0, "no cpu runs threads yet");
+    let nth = NEXT.fetch_add(1, Ordering::Relaxed) % count;
+    cpus().nth(nth).map(|cpu| cpu.index).unwrap()
+}
+
+/// Puts new thread into the ready queue of its cpu
+pub(super) fn add(thread: Arc<Thread>) {
+    push_ready(thread);
+}
+
+fn push_ready(thread: Arc<Thread>) {
+    let cpu = percpu::cpu(thread.cpu).expect("thread belongs to a cpu that is not online");
+    let is_this_cpu = percpu::this_cpu().index == cpu.index;
+    cpu.scheduler.ready.lock()[thread.priority.index()].push_back(thread);
+    // if it's this cpu it checks the queues on the next timer tick or once it blocks anyway
+    if cpu.is_idle() && !is_this_cpu {
+        ipi::send_ipi(IpiTarget::Cpu(cpu.index), ipi::WAKEUP_VECTOR);
+    }
+}
+
+/// Moves blocked thread back to the ready queue of its cpu, does nothing for other states.
+/// Safe to call from interrupt handlers.
+pub(crate) fn wake(thread: &Arc<Thread>) {
+    let woken = thread.state.compare_exchange(
+        ThreadState::Blocked as u8,
+        ThreadState::Ready as u8,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    );
+    if woken.is_ok() {
+        push_ready(thread.clone());
+    }
+}
+
+/// Wakes the main thread of `cpu` if it's blocked (eg. because its executor has nothing to do)
+pub(crate) fn wake_main_thread(cpu: &PerCpu) {
+    if let Ok(thread) = cpu.scheduler.main_thread.try_get() {
+        wake(thread);
+    }
+}
+
+pub(crate) fn is_main_thread_blocked(cpu: &PerCpu) -> bool {
+    cpu.scheduler
+        .main_thread
+        .try_get()
+        .is_ok_and(|thread| thread.state() == ThreadState::Blocked)
+}
+
+/// First step of blocking, after it `wake` can move the thread back to the ready queue.
+/// Check the condition you wait for after this and before `block`, otherwise a wakeup that
+/// comes in between is lost.
+pub(crate) fn prepare_to_block() {
+    let thread = current(percpu::this_cpu()).expect("threads are not initialized on this cpu");
+    thread.set_state(ThreadState::Blocked);
+}
+
+/// Undoes `prepare_to_block` when it turns out the thread doesn't have to wait
+pub(crate) fn cancel_block() {
+    let thread = current(percpu::this_cpu()).expect("threads are not initialized on this cpu");
+    let cancelled = thread.state.compare_exchange(
+        ThreadState::Blocked as u8,
+        ThreadState::Running as u8,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    );
+    drop(thread);
+    if cancelled.is_err() {
+        // already woken, so it's in the ready queue and has to go through it
+        block();
+    }
+}
+
+/// Switches to other threads until this one is woken, returns right away if it already was
+pub(crate) fn block() {
+    interrupts::without_interrupts(|| reschedule(Reason::Block));
+}
+
+pub(super) fn yield_now() {
+    interrupts::without_interrupts(|| reschedule(Reason::Yield));
+}
+
+pub(super) fn exit() -> ! {
+    interrupts::disable();
+    let thread = current(percpu::this_cpu()).expect("threads are not initialized on this cpu");
+    assert!(!thread.is_main(), "main thread of a cpu can't exit");
+    thread.set_state(ThreadState::Finished);
+    thread.exit_waiters.notify_all();
+    drop(thread);
+    reschedule(Reason::Exit);
+    unreachable!("finished thread was switched back to");
+}
+
+/// Called by the LAPIC timer interrupt handler of every cpu
+pub(crate) fn on_timer_tick() {
+    let cpu = percpu::this_cpu();
+    if !cpu.scheduler.main_thread.is_initialized() {
+        return;
+    }
+    let slice_over = cpu.scheduler.ticks_left.fetch_sub(1, Ordering::Relaxed) <= 1;
+    let current_priority = match current(cpu) {
+        Some(thread) => thread.priority,
+        None => return,
+    };
+    let higher_ready = current_priority.index() > 0
+        && cpu.scheduler.ready.lock()[..current_priority.index()]
+            .iter()
+            .any(|queue| !queue.is_empty());
+    if slice_over || higher_ready {
+        reschedule(Reason::Yield);
+    }
+}
+
+enum Reason {
+    /// current thread stays ready, only gives way to threads that are at least as important
+    Yield,
+    /// current thread was marked as blocked by `prepare_to_block`
+    Block,
+    /// current thread was marked as finished
+    Exit,
+}
+
+// WARN: interrupts have to be disabled
+fn reschedule(reason: Reason) {
+    let cpu = percpu::this_cpu();
+    let scheduler = &cpu.scheduler;
+    let current = current(cpu).expect("threads are not initialized on this cpu");
+
+    let next = match reason {
+        Reason::Yield => {
+            // blocked thread can be preempted while it waits for the next one in `wait_for_ready`
+            if current.state() != ThreadState::Running {
+                return;
+            }
+            let Some(next) = scheduler.pop_ready(current.priority) else {
+                scheduler
+                    .ticks_left
+                    .store(TIME_SLICE_TICKS, Ordering::Relaxed);
+                return;
+            };
+            current.set_state(ThreadState::Ready);
+            scheduler.ready.lock()[current.priority.index()].push_back(current.clone());
+            next
+        }
+        // when it was woken already it's in the ready queue and can be picked again
+        Reason::Block | Reason::Exit => wait_for_ready(cpu),
+    };
+
+    if Arc::ptr_eq(&next, &current) {
+        current.set_state(ThreadState::Running);
+        return;
+    }
+    switch_to(cpu, current, next);
+}
+
+/// Halts the cpu until some thread is ready
+fn wait_for_ready(cpu: &PerCpu) -> Arc<Thread> {
+    loop {
+        if let Some(next) = cpu.scheduler.pop_ready(ThreadPriority::Low) {
+            return next;
+        }
+        // wakers on other cpus send IPI only when this flag is set
+        cpu.set_idle(true);
+        if cpu.scheduler.has_ready() {
+            cpu.set_idle(false);
+            continue;
+        }
+        interrupts::enable_and_hlt();
+        interrupts::disable();
+        cpu.set_idle(false);
+    }
+}
+
+fn switch_to(cpu: &PerCpu, current: Arc<Thread>, next: Arc<Thread>) {
+    current.save_cpu_state(cpu);
+    next.restore_cpu_state(cpu);
+    next.set_state(ThreadState::Running);
+    cpu.scheduler
+        .ticks_left
+        .store(TIME_SLICE_TICKS, Ordering::Relaxed);
+
+    let old_stack_pointer = current.stack_pointer.get();
+    let new_stack_pointer = unsafe { *next.stack_pointer.get() };
+    // no locks can be held and no `Arc`s can be left on this stack, it might never run again
+    *cpu.scheduler.current.lock() = Some(next);
+    *cpu.scheduler.previous.lock() = Some(current);
+
+    unsafe { context::switch_context(old_stack_pointer, new_stack_pointer) };
+    after_switch();
+}
+
+/// Has to run right after every switch, on the new thread's stack
+pub(super) fn after_switch() {
+    let previous = percpu::this_cpu().scheduler.previous.lock().take();
+    // finished thread's stack is freed here
+    drop(previous);
+}
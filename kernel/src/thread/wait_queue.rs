@@ -0,0 +1,67 @@
+use alloc::{collections::VecDeque, sync::Arc};
+
+use super::{Thread, scheduler};
+use crate::sync::IrqSafeMutex;
+
+/// Threads that are blocked (not spinning) until some condition changes.
+/// Notifying is safe from interrupt handlers, waiting is not.
+pub struct WaitQueue {
+    waiters: IrqSafeMutex<VecDeque<Arc<Thread>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqSafeMutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the current thread until `condition` returns true.
+    /// The condition is checked after the thread is queued, so a notification that comes between
+    /// the check and blocking isn't lost.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        let thread = super::current().expect("threads are not initialized on this cpu");
+        loop {
+            scheduler::prepare_to_block();
+            self.waiters.lock().push_back(thread.clone());
+            if condition() {
+                self.remove(&thread);
+                scheduler::cancel_block();
+                return;
+            }
+            scheduler::block();
+            // still queued if it was woken by something else
+            self.remove(&thread);
+        }
+    }
+
+    fn remove(&self, thread: &Arc<Thread>) {
+        self.waiters
+            .lock()
+            .retain(|waiter| !Arc::ptr_eq(waiter, thread));
+    }
+
+    /// Wakes the thread that waits the longest, returns false if nobody waits
+    pub fn notify_one(&self) -> bool {
+        let Some(thread) = self.waiters.lock().pop_front() else {
+            return false;
+        };
+        scheduler::wake(&thread);
+        true
+    }
+
+    /// Returns how many threads were woken
+    pub fn notify_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for thread in waiters.iter() {
+            scheduler::wake(thread);
+        }
+        waiters.len()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
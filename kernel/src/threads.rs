@@ -1,34 +1,153 @@
-use x86::apic::{self, ApicControl, ApicId};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use crate::interrupts;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use x86::apic::{ApicControl, ApicId};
+
+use crate::{
+    boot_config,
+    interrupts::{self, apic::ProcessorsInfo},
+    time,
+};
 
 pub mod ap_entrypoint;
 mod trampoline;
+
+// delays from Intel MP spec
+const INIT_DELAY_US: u64 = 10_000;
+const SIPI_DELAY_US: u64 = 200;
+/// how long to wait for AP to report online before giving up on it
+const AP_ONLINE_TIMEOUT_US: u64 = 100_000;
+
+pub struct Cpu {
+    pub apic_id: u32,
+    online: AtomicBool,
+}
+impl Cpu {
+    fn new(apic_id: u32) -> Cpu {
+        Cpu {
+            apic_id,
+            online: AtomicBool::new(false),
+        }
+    }
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+}
+
+/// All usable cpus from MADT, index 0 -> bootstrap processor
+pub static CPUS: OnceCell<Vec<Cpu>> = OnceCell::uninit();
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+/// Number of cpus that finished startup (including bootstrap processor)
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::Acquire)
+}
+
+/// Index of the cpu in `CPUS`
+pub fn cpu_index(apic_id: u32) -> Option<usize> {
+    CPUS.get()?.iter().position(|cpu| cpu.apic_id == apic_id)
+}
+
+/// Called by AP when it finished its initialization and no longer needs trampoline data
+pub(crate) fn report_online(apic_id: u32) {
+    let cpu = cpu_index(apic_id).and_then(|index| CPUS.get().unwrap().get(index));
+    match cpu {
+        Some(cpu) => {
+            cpu.online.store(true, Ordering::Release);
+            ONLINE_CPUS.fetch_add(1, Ordering::AcqRel);
+        }
+        None => log::error!("AP with unknown apic id: {apic_id} reported online"),
+    }
+}
+
+/// Starts the APs one by one and waits for each to report online.
+/// If one doesn't in `AP_ONLINE_TIMEOUT_US`, the rest are not started: it could still wake up
+/// later and would then read the trampoline data of the next AP, so 2 cpus would share a stack.
 pub fn init(
-    ap_count: u8,
+    processors: ProcessorsInfo,
     level_4_table_phys_address: u64,
     gdt_base_phys_address: u64,
     gdt_size: usize,
 ) {
-    trampoline::init(ap_count as usize);
+    // `smp` and `cpus` boot options can leave some of them unused
+    let usable_cpus = boot_config::get().usable_cpus(processors.ap_apic_ids.len() + 1);
+    if usable_cpus <= processors.ap_apic_ids.len() {
+        log::info!(
+            "using {usable_cpus} of {} cpus",
+            processors.ap_apic_ids.len() + 1
+        );
+    }
+    let cpus = CPUS.get_or_init(|| {
+        let mut cpus = Vec::with_capacity(usable_cpus);
+        cpus.push(Cpu::new(processors.bsp_apic_id));
+        cpus.extend(
+            processors.ap_apic_ids[..usable_cpus - 1]
+                .iter()
+                .map(|id| Cpu::new(*id)),
+        );
+        cpus
+    });
+    cpus[0].online.store(true, Ordering::Release);
+
+    let ap_count = cpus.len() - 1;
+    trampoline::init(ap_count);
 
     // 0-> bootstrap processor
-    // init all aps
-    for ap_index in 1..ap_count + 1 {
+    // init all aps one by one, they all share the same trampoline data
+    for (cpu_index, cpu) in cpus.iter().enumerate().skip(1) {
         trampoline::setup_trampoline_data(
-            ap_index,
+            cpu_index,
             level_4_table_phys_address,
             gdt_base_phys_address,
             gdt_size,
         );
 
-        // unsafe { interrupts::apic::xapic().ipi_init(ApicId::XApic(ap_index)) };
+        start_ap(cpu);
+
+        if !wait_for_online(cpu, AP_ONLINE_TIMEOUT_US) {
+            // its trampoline data has to stay as it is, in case it's only slow
+            log::error!(
+                "AP with apic id: {} didn't report online in {AP_ONLINE_TIMEOUT_US} us, not starting the remaining {} APs",
+                cpu.apic_id,
+                ap_count - cpu_index
+            );
+            break;
+        }
+    }
+    log::debug!("initialized ap threads, online cpus: {}", online_cpus());
+}
+
+/// INIT - 10 ms - SIPI - 200 us - SIPI
+fn start_ap(cpu: &Cpu) {
+    let apic_id = ApicId::XApic(cpu.apic_id as u8);
+    let mut xapic = interrupts::apic::xapic();
+
+    unsafe {
+        xapic.ipi_init(apic_id);
+        xapic.ipi_init_deassert();
+    }
+    time::busy_wait_us(INIT_DELAY_US);
+
+    for _ in 0..2 {
+        unsafe { xapic.ipi_startup(apic_id, trampoline::TRAMPOLINE_START_PAGE) };
+        time::busy_wait_us(SIPI_DELAY_US);
+        // second SIPI is only needed if the first one was lost
+        if cpu.is_online() {
+            return;
+        }
+    }
+}
 
-        unsafe { interrupts::apic::xapic().ipi_init(ApicId::XApic(ap_index)) };
-        unsafe {
-            interrupts::apic::xapic()
-                .ipi_startup(ApicId::XApic(ap_index), trampoline::TRAMPOLINE_ADDR as u8)
-        };
+fn wait_for_online(cpu: &Cpu, timeout_us: u64) -> bool {
+    const STEP_US: u64 = 100;
+    let mut waited_us = 0;
+    while !cpu.is_online() {
+        if waited_us >= timeout_us {
+            return false;
+        }
+        time::busy_wait_us(STEP_US);
+        waited_us += STEP_US;
     }
-    log::debug!("initialized ap threads");
+    true
 }
@@ -1,15 +1,20 @@
-use core::sync::atomic::AtomicBool;
-
-use x86::task::tr;
-
-pub static test: AtomicBool = AtomicBool::new(false);
+use crate::{gdt, interrupts, percpu, task, thread, threads};
 
 #[unsafe(no_mangle)]
 pub extern "C" fn ap_entrypoint() -> ! {
-    // Setup per-core GDT/IDT/etc.
-    log::info!("AP core online!");
-    test.store(true, core::sync::atomic::Ordering::Relaxed);
-    loop {
-        x86_64::instructions::hlt();
-    }
+    let apic_id = interrupts::apic::current_apic_id();
+    let cpu_index = threads::cpu_index(apic_id).expect("AP apic id is not in MADT");
+    percpu::init(cpu_index, apic_id);
+
+    // the trampoline GDT has no TSS, so replace it before taking any interrupts
+    gdt::init_ap();
+    interrupts::apic::init_ap();
+
+    // from now on trampoline data can be overwritten for the next AP
+    threads::report_online(apic_id);
+
+    log::info!("AP core online! apic id: {apic_id}");
+    // tasks are spawned by bootstrap processor, this cpu takes them from the shared queues
+    thread::init_cpu();
+    task::executor::Executor::new().run();
 }
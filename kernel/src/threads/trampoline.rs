@@ -8,6 +8,10 @@ pub struct TrampolineData {
 }
 
 pub const TRAMPOLINE_ADDR: usize = 0x8000;
+// has to match `TRAMPOLINE_DATA_PTR` in trampoline.s
+pub const TRAMPOLINE_DATA_ADDR: usize = 0x9000;
+// SIPI vector is the number of the page that the AP starts executing at
+pub const TRAMPOLINE_START_PAGE: u8 = (TRAMPOLINE_ADDR >> 12) as u8;
 
 use core::ptr;
 
@@ -22,8 +26,11 @@ use crate::{
     memory::{self, PER_AP_STACK_STACK_SIZE},
     threads::ap_entrypoint::ap_entrypoint,
 };
-const fn stack_ptr(cpu_id: u8) -> u64 {
-    (memory::AP_STACK_MEMORY_START + memory::PER_AP_STACK_STACK_SIZE * cpu_id as usize) as u64
+// `cpu_index` starts at 1 for the first AP, so it points at the top of its stack
+const fn stack_ptr(cpu_index: usize) -> u64 {
+    let top = memory::AP_STACK_MEMORY_START + memory::PER_AP_STACK_STACK_SIZE * cpu_index;
+    // SysV ABI wants 16 byte aligned stack
+    (top & !0xF) as u64
 }
 
 fn allocate_memory_for_stacks(cpu_count: usize) {
@@ -38,10 +45,12 @@ fn allocate_memory_for_stacks(cpu_count: usize) {
 
 static TRAMPOLINE_BIN: &[u8] = include_bytes!("trampoline.bin");
 fn load_trampoline() {
+    // AP starts in real mode at physical address of the trampoline, and keeps executing it after
+    // enabling paging, so it has to be identity mapped
     unsafe {
-        memory::map_memory(
-            0x8000,
-            0x9000 - 0x8000 + 1,
+        memory::identity_map_memory(
+            TRAMPOLINE_ADDR,
+            TRAMPOLINE_DATA_ADDR - TRAMPOLINE_ADDR + size_of::<TrampolineData>(),
             PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
         )
     };
@@ -60,20 +69,22 @@ pub fn init(ap_count: usize) {
     allocate_memory_for_stacks(ap_count);
     load_trampoline();
 }
+/// There is only one `TrampolineData` slot, so wait for AP to report online before calling this
+/// for the next one.
 pub fn setup_trampoline_data(
-    ap_id: u8,
+    cpu_index: usize,
     level_4_table_phys_address: u64,
     gdt_base_phys_address: u64,
     gdt_size: usize,
 ) {
     let data = TrampolineData {
-        ap_stack_ptr: stack_ptr(ap_id),
+        ap_stack_ptr: stack_ptr(cpu_index),
         ap_entry_point_address: ap_entrypoint as u64,
         level_4_table_phys_address,
         gdt_start_address: gdt_base_phys_address,
         gdt_size: gdt_size as u16,
     };
 
-    let dst = 0x9000 as *mut TrampolineData;
+    let dst = TRAMPOLINE_DATA_ADDR as *mut TrampolineData;
     unsafe { ptr::write(dst, data) };
 }
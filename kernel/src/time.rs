@@ -1,14 +1,122 @@
+mod interval;
+
+use core::{
+    ops::{Add, AddAssign, Sub},
+    pin::pin,
+    time::Duration,
+};
+
+use futures_util::future::{Either, select};
 use x86::apic::ioapic;
 
 use crate::interrupts::apic::{self, xapic};
+
+pub use interval::{Interval, MissedTickBehavior, interval, interval_at};
+
 /// # SAFETY
 /// Shouldn't cause any deadlocks
 pub async fn wait_ms(length_ms: u64) {
-    let time_waker = TimeWaker::new(length_ms, AtomicWaker::new());
-    let waker_arc: Arc<TimeWaker> = Arc::new(time_waker);
-    TIME_WAKERS.push(waker_arc.clone());
-    let mut time_waiter = TimeWaiter::new(waker_arc);
-    time_waiter.await;
+    TimeWaiter::new(TIME_MS.load(Ordering::Relaxed).saturating_add(length_ms)).await;
+}
+
+/// Point in time with 1 ms resolution, counted from the start of the timer (same as `TIME_MS`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(TIME_MS.load(Ordering::Relaxed))
+    }
+    pub fn from_ms(ms: u64) -> Instant {
+        Instant(ms)
+    }
+    pub fn as_ms(self) -> u64 {
+        self.0
+    }
+    /// Zero if `earlier` is actually later
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_millis(self.0.saturating_sub(earlier.0))
+    }
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+}
+
+// timers can't fire in the middle of a ms, so partial ms are rounded up to never wake too early
+fn duration_to_ms(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos().div_ceil(1_000_000)).unwrap_or(u64::MAX)
+}
+
+/// Saturates, so `Duration::MAX` is a deadline that is never reached
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration_to_ms(duration)))
+    }
+}
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+pub fn sleep(duration: Duration) -> TimeWaiter {
+    sleep_until(Instant::now() + duration)
+}
+
+pub fn sleep_until(deadline: Instant) -> TimeWaiter {
+    TimeWaiter::new(deadline.as_ms())
+}
+
+/// `future` didn't complete before its deadline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Drops `future` if it doesn't complete within `duration`
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    timeout_at(Instant::now() + duration, future).await
+}
+
+pub async fn timeout_at<F: Future>(deadline: Instant, future: F) -> Result<F::Output, Elapsed> {
+    // future is polled first, so it wins if both are ready
+    match select(pin!(future), sleep_until(deadline)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(Elapsed),
+    }
+}
+
+/// Spins for at least `length_us` microseconds using TSC.
+/// Doesn't depend on interrupts, so it can be used during early init (eg. AP startup)
+pub fn busy_wait_us(length_us: u64) {
+    let tsc_hz = *crate::interrupts::TSC_HZ
+        .try_get()
+        .expect("TSC was not yet calibrated");
+    let ticks = tsc_hz * length_us / 1_000_000;
+    let start = unsafe { x86::time::rdtsc() };
+    while unsafe { x86::time::rdtsc() } - start < ticks {
+        core::hint::spin_loop();
+    }
+}
+
+/// Current value of the TSC, cheap enough to be read on every task poll
+pub fn read_tsc() -> u64 {
+    unsafe { x86::time::rdtsc() }
+}
+
+/// Returns 0 when TSC was not yet calibrated
+pub fn tsc_ticks_to_us(ticks: u64) -> u64 {
+    let Ok(tsc_hz) = crate::interrupts::TSC_HZ.try_get() else {
+        return 0;
+    };
+    (ticks as u128 * 1_000_000 / *tsc_hz as u128) as u64
 }
 
 static TIMER_FIRED: AtomicBool = AtomicBool::new(false);
@@ -22,13 +130,14 @@ pub fn on_1ms_timer_interrupt() {
     WAKER.wake();
 }
 
-use alloc::{string::String, sync::Arc, vec::Vec};
+use alloc::{collections::BTreeMap, sync::Arc};
 use conquer_once::spin::OnceCell;
-use crossbeam_queue::{ArrayQueue, SegQueue};
 
 use log::*;
 use spin::Mutex;
 
+use crate::sync::IrqSafeMutex;
+
 use core::{
     pin::Pin,
     sync::atomic::{AtomicBool, AtomicU64, Ordering},
@@ -53,7 +162,14 @@ pub fn wait_for_next_interrupt() -> WaitForInterrupt {
     WaitForInterrupt
 }
 
-pub static TIME_WAKERS: SegQueue<Arc<TimeWaker>> = SegQueue::new();
+// (end time in ms, timer id) - id makes keys of timers with the same end time unique
+type TimerKey = (u64, u64);
+
+/// Sleeping timers sorted by end time, so every tick only touches the ones that expired and
+/// a cancelled timer is removed in O(log n)
+static TIMERS: IrqSafeMutex<BTreeMap<TimerKey, Arc<TimeWaker>>> =
+    IrqSafeMutex::new(BTreeMap::new());
+
 pub(crate) async fn run_timer_loop() {
     debug!("run_timer_loop");
 
@@ -66,59 +182,167 @@ pub(crate) async fn run_timer_loop() {
 }
 
 fn update_time_wakers(current_time: u64) {
-    let mut retained = Vec::new();
-    while let Some(waker) = TIME_WAKERS.pop() {
-        if !waker.update(current_time) {
-            retained.push(waker);
+    let mut timers = TIMERS.lock();
+    while let Some(timer) = timers.first_entry() {
+        if timer.key().0 > current_time {
+            break;
         }
-    }
-    for w in retained {
-        TIME_WAKERS.push(w);
+        timer.remove().fire();
     }
 }
+
+/// Completes once `TIME_MS` reaches its end time.
+/// Dropping it before that removes its timer.
 pub struct TimeWaiter {
     time_waker: Arc<TimeWaker>,
 }
-pub struct TimeWaker {
+struct TimeWaker {
     end_time: u64,
+    id: u64,
 
     wake: AtomicBool,
     waker: AtomicWaker,
 }
 
 impl TimeWaker {
-    pub fn new(length_ms: u64, waker: AtomicWaker) -> Self {
-        TimeWaker {
-            end_time: TIME_MS.load(Ordering::Relaxed) + length_ms,
-            wake: AtomicBool::new(false),
-            waker,
-        }
+    fn key(&self) -> TimerKey {
+        (self.end_time, self.id)
     }
-    // checks current time and tells weather it used its waker
-    pub fn update(&self, current_time: u64) -> bool {
-        if current_time < self.end_time {
-            return false;
-        }
-        self.wake.store(true, Ordering::Relaxed);
+    fn fire(&self) {
+        self.wake.store(true, Ordering::Release);
         self.waker.wake();
-        true
     }
 }
 impl TimeWaiter {
-    pub fn new(waker: Arc<TimeWaker>) -> Self {
-        TimeWaiter { time_waker: waker }
+    /// `end_time` is in ms, same as `TIME_MS`
+    pub fn new(end_time: u64) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        let time_waker = Arc::new(TimeWaker {
+            end_time,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            wake: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        });
+        TIMERS.lock().insert(time_waker.key(), time_waker.clone());
+        TimeWaiter { time_waker }
+    }
+    pub fn end_time(&self) -> u64 {
+        self.time_waker.end_time
+    }
+    fn is_done(&self) -> bool {
+        self.time_waker.wake.load(Ordering::Acquire)
+            || TIME_MS.load(Ordering::Relaxed) >= self.time_waker.end_time
     }
 }
 impl Future for TimeWaiter {
     type Output = ();
 
     fn poll(self: core::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if self.time_waker.wake.load(Ordering::Relaxed) {
+        // fast path
+        if self.is_done() {
+            return Poll::Ready(());
+        }
+
+        self.time_waker.waker.register(cx.waker());
+        if self.is_done() {
             self.time_waker.waker.take();
-            Poll::Ready(())
-        } else {
-            self.time_waker.waker.register(cx.waker());
-            Poll::Pending
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+impl Drop for TimeWaiter {
+    fn drop(&mut self) {
+        if !self.time_waker.wake.load(Ordering::Acquire) {
+            TIMERS.lock().remove(&self.time_waker.key());
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread::block_on;
+
+    #[test_case]
+    fn instant_arithmetic() {
+        let start = Instant::from_ms(100);
+        assert_eq!((start + Duration::from_millis(50)).as_ms(), 150);
+        // partial ms round up
+        assert_eq!((start + Duration::from_micros(1)).as_ms(), 101);
+        assert_eq!(Instant::from_ms(150) - start, Duration::from_millis(50));
+        assert_eq!(start - Instant::from_ms(150), Duration::ZERO);
+        assert_eq!((start + Duration::MAX).as_ms(), u64::MAX);
+    }
+
+    #[test_case]
+    fn sleep_waits_long_enough() {
+        let start = Instant::now();
+        block_on(sleep(Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test_case]
+    fn timeout_elapses() {
+        let result = block_on(timeout(
+            Duration::from_millis(10),
+            sleep(Duration::from_secs(60)),
+        ));
+        assert_eq!(result, Err(Elapsed));
+    }
+
+    #[test_case]
+    fn timeout_completes() {
+        let result = block_on(timeout(Duration::from_secs(60), async { 7 }));
+        assert_eq!(result, Ok(7));
+    }
+
+    // started 35 ms ago, so the first tick is 3 periods late
+    fn late_interval(behavior: MissedTickBehavior) -> (Interval, Instant) {
+        let start = Instant::from_ms(Instant::now().as_ms().saturating_sub(35));
+        let mut interval = interval_at(start, Duration::from_millis(10));
+        interval.set_missed_tick_behavior(behavior);
+        (interval, start)
+    }
+
+    #[test_case]
+    fn burst_fires_missed_ticks_right_away() {
+        let (mut interval, start) = late_interval(MissedTickBehavior::Burst);
+        assert_eq!(block_on(interval.tick()), start);
+        let before = Instant::now();
+        assert_eq!(block_on(interval.tick()), start + Duration::from_millis(10));
+        assert_eq!(block_on(interval.tick()), start + Duration::from_millis(20));
+        // still catching up, so neither had to wait
+        assert!(before.elapsed() < Duration::from_millis(10));
+    }
+
+    #[test_case]
+    fn delay_shifts_schedule_to_late_tick() {
+        let (mut interval, start) = late_interval(MissedTickBehavior::Delay);
+        let before = Instant::now();
+        assert_eq!(block_on(interval.tick()), start);
+        let after = Instant::now();
+        let next = block_on(interval.tick());
+        assert!(next >= before + Duration::from_millis(10));
+        assert!(next <= after + Duration::from_millis(10));
+    }
+
+    #[test_case]
+    fn skip_keeps_original_schedule() {
+        let (mut interval, start) = late_interval(MissedTickBehavior::Skip);
+        let before = Instant::now();
+        assert_eq!(block_on(interval.tick()), start);
+        let after = Instant::now();
+        let next = block_on(interval.tick());
+        assert!(next > before && next <= after + Duration::from_millis(10));
+        assert_eq!((next - start).as_millis() % 10, 0);
+    }
+
+    #[test_case]
+    fn busy_wait_uses_tsc() {
+        let start = read_tsc();
+        busy_wait_us(1000);
+        assert!(tsc_ticks_to_us(read_tsc() - start) >= 1000);
+    }
+}
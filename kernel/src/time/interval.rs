@@ -0,0 +1,104 @@
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use futures_util::stream::Stream;
+
+use super::{Instant, TimeWaiter, duration_to_ms, sleep_until};
+
+/// What `Interval` does when ticks were missed because the task was busy (or wasn't polled)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissedTickBehavior {
+    /// missed ticks fire right away one after another until it catches up
+    #[default]
+    Burst,
+    /// next tick is one `period` after the late one, the schedule gets shifted
+    Delay,
+    /// missed ticks are dropped, next one is on the original schedule
+    Skip,
+}
+
+/// Ticks every `period`, created by `interval` and `interval_at`
+pub struct Interval {
+    period: Duration,
+    next_tick: Instant,
+    missed_tick_behavior: MissedTickBehavior,
+    sleep: TimeWaiter,
+}
+
+/// First tick completes immediately
+pub fn interval(period: Duration) -> Interval {
+    interval_at(Instant::now(), period)
+}
+
+pub fn interval_at(start: Instant, period: Duration) -> Interval {
+    assert!(!period.is_zero(), "interval period can't be zero");
+    Interval {
+        period,
+        next_tick: start,
+        missed_tick_behavior: MissedTickBehavior::default(),
+        sleep: sleep_until(start),
+    }
+}
+
+impl Interval {
+    /// Returns the moment the tick was scheduled for, which can be earlier than now
+    pub async fn tick(&mut self) -> Instant {
+        core::future::poll_fn(|cx| self.poll_tick(cx)).await
+    }
+
+    pub fn poll_tick(&mut self, cx: &mut Context) -> Poll<Instant> {
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let tick = self.next_tick;
+        let now = Instant::now();
+        self.next_tick = if now >= tick + self.period {
+            self.after_missed_ticks(tick, now)
+        } else {
+            tick + self.period
+        };
+        self.sleep = sleep_until(self.next_tick);
+        Poll::Ready(tick)
+    }
+
+    fn after_missed_ticks(&self, tick: Instant, now: Instant) -> Instant {
+        match self.missed_tick_behavior {
+            MissedTickBehavior::Burst => tick + self.period,
+            MissedTickBehavior::Delay => now + self.period,
+            MissedTickBehavior::Skip => {
+                let period_ms = duration_to_ms(self.period);
+                let late_ms = now.as_ms() - tick.as_ms();
+                now + Duration::from_millis(period_ms - late_ms % period_ms)
+            }
+        }
+    }
+
+    /// Next tick is one `period` from now
+    pub fn reset(&mut self) {
+        self.next_tick = Instant::now() + self.period;
+        self.sleep = sleep_until(self.next_tick);
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn missed_tick_behavior(&self) -> MissedTickBehavior {
+        self.missed_tick_behavior
+    }
+
+    pub fn set_missed_tick_behavior(&mut self, behavior: MissedTickBehavior) {
+        self.missed_tick_behavior = behavior;
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Instant>> {
+        self.get_mut().poll_tick(cx).map(Some)
+    }
+}
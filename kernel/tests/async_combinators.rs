@@ -0,0 +1,110 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader_api::{BootInfo, entry_point};
+use core::{
+    future,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use kernel::{
+    join, select,
+    task::sync::{mpsc, oneshot},
+    thread::block_on,
+    time::{self, Instant},
+};
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::testing::run(boot_info, test_main);
+}
+
+#[test_case]
+fn select_takes_first_ready() {
+    let winner = block_on(async {
+        select! {
+            _ = time::sleep(Duration::from_secs(60)) => 1,
+            value = async { 2 } => value,
+        }
+    });
+    assert_eq!(winner, 2);
+}
+
+#[test_case]
+fn select_prefers_earlier_branch() {
+    let winner = block_on(async {
+        select! {
+            first = future::ready(1) => first,
+            second = future::ready(2) => second,
+        }
+    });
+    assert_eq!(winner, 1);
+}
+
+/// Sets the flag when dropped
+struct DropFlag<'a>(&'a AtomicBool);
+impl Drop for DropFlag<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+#[test_case]
+fn select_drops_losers_before_handler() {
+    let dropped = AtomicBool::new(false);
+    let dropped_in_handler = block_on(async {
+        select! {
+            _ = async {
+                let _flag = DropFlag(&dropped);
+                future::pending::<()>().await
+            } => unreachable!(),
+            _ = future::ready(()) => dropped.load(Ordering::Acquire),
+        }
+    });
+    assert!(dropped_in_handler);
+}
+
+#[test_case]
+fn join_waits_for_all() {
+    let start = Instant::now();
+    let (a, b, _) = block_on(async {
+        join!(
+            async { 1 },
+            async {
+                time::sleep(Duration::from_millis(20)).await;
+                2
+            },
+            time::sleep(Duration::from_millis(10)),
+        )
+    });
+    assert_eq!((a, b), (1, 2));
+    // run concurrently, not one after another
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    assert!(start.elapsed() < Duration::from_millis(1000));
+}
+
+#[test_case]
+fn channels_between_tasks() {
+    let (sender, mut receiver) = mpsc::channel(4);
+    let (done, done_receiver) = oneshot::channel();
+    kernel::task::executor::TASK_SPAWNER.spawn(async move {
+        for i in 0..10u32 {
+            sender.send(i).await.unwrap();
+        }
+        let _ = done.send(());
+    });
+    let sum = block_on(async {
+        let mut sum = 0;
+        while let Some(value) = receiver.recv().await {
+            sum += value;
+        }
+        sum
+    });
+    assert_eq!(sum, 45);
+    assert!(block_on(done_receiver).is_ok());
+}
@@ -0,0 +1,33 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, string::ToString};
+use bootloader_api::{BootInfo, entry_point};
+use kernel::{percpu, threads};
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::testing::run(boot_info, test_main);
+}
+
+#[test_case]
+fn logging_works() {
+    log::info!("log from a test");
+}
+
+#[test_case]
+fn heap_works_after_boot() {
+    let text = Box::new("boot".to_string());
+    assert_eq!(text.as_str(), "boot");
+}
+
+#[test_case]
+fn every_cpu_is_online() {
+    assert_eq!(percpu::all_cpus().count(), threads::online_cpus());
+    assert!(percpu::cpu(0).is_some());
+}
@@ -0,0 +1,50 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader_api::{BootInfo, entry_point};
+use kernel::thread::{self, Mutex};
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::testing::run(boot_info, test_main);
+}
+
+#[test_case]
+fn join_returns_output() {
+    let handle = thread::spawn(|| 6 * 7);
+    assert_eq!(handle.join(), Ok(42));
+}
+
+#[test_case]
+fn mutex_between_threads() {
+    let counter = Arc::new(Mutex::new(0u64));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    *counter.lock() += 1;
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(*counter.lock(), 4000);
+}
+
+#[test_case]
+fn sleep_blocks_only_this_thread() {
+    let start = kernel::time::Instant::now();
+    let handle = thread::spawn(|| thread::sleep_ms(20));
+    thread::sleep_ms(20);
+    handle.join().unwrap();
+    assert!(start.elapsed() < core::time::Duration::from_millis(1000));
+}
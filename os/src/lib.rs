@@ -2,7 +2,7 @@
 
 pub mod graphics;
 
-use core::u8;
+use core::time::Duration;
 
 use alloc::{
     boxed::Box,
@@ -10,17 +10,19 @@ use alloc::{
 };
 use conquer_once::spin::OnceCell;
 use graphics::*;
-use kernel::task::StaticTask;
+use kernel::{
+    logger::{FilterError, LogFilter, LogRecord},
+    task::join::JoinHandle,
+};
 use pc_keyboard::DecodedKey;
 use spin::Mutex;
 extern crate alloc;
 /// should be called after kernel is initialized
 pub fn init_os() {
-    kernel::task::keyboard::ON_KEY_PRESSED_LISTENERS
-        .lock()
-        .push(on_key_pressed);
+    kernel::task::keyboard::ON_KEY_PRESSED_LISTENERS.register(on_key_pressed);
 
-    kernel::logger::ON_LOG_LISTENERS.lock().push(on_log);
+    kernel::logger::ON_LOG_LISTENERS.register(on_log);
+    exec_async_task(run_on_time_loop());
 
     SCREEN_SIZE_PIXELS.init_once(move || {
         let info = kernel::graphics::RENDERER
@@ -39,8 +41,41 @@ pub fn init_os() {
 // pub fn listen_to_logs(&mut) {
 //     kernel::logger::ON_LOG_LISTENERS.lock().push(function);
 // }
-pub fn exec_async_task(future: impl Future<Output = ()> + 'static + Send) {
-    kernel::task::executor::TASK_SPAWNER.spawn(StaticTask::new(future));
+pub fn exec_async_task<T: Send + 'static>(
+    future: impl Future<Output = T> + 'static + Send,
+) -> JoinHandle<T> {
+    kernel::task::executor::TASK_SPAWNER.spawn(future)
+}
+pub fn task_list() -> Vec<kernel::task::TaskInfo> {
+    kernel::task::task_list()
+}
+pub fn log_filter() -> LogFilter {
+    kernel::logger::current_filter()
+}
+/// `target=level` directives separated by commas, see `kernel::logger::LogFilter::apply`
+pub fn apply_log_directives(directives: &str) -> Result<(), FilterError> {
+    kernel::logger::apply_directives(directives)
+}
+/// Bytes received over the serial line, can only be called once
+pub fn serial_input() -> kernel::serial::SerialStream {
+    kernel::serial::SerialStream::new()
+}
+/// Waits while the serial transmit buffer is full
+pub async fn serial_write(bytes: &[u8]) {
+    kernel::serial::write(bytes).await
+}
+/// Straight to the serial line, bypasses logs (and their filter)
+pub fn serial_print(args: core::fmt::Arguments) {
+    kernel::logger::print(args)
+}
+/// Options the kernel was booted with, see `kernel::boot_config`
+pub fn boot_config() -> &'static kernel::boot_config::BootConfig {
+    kernel::boot_config::get()
+}
+/// Every log that is still kept in the kernel log buffer, oldest first
+pub fn dmesg() -> Vec<LogRecord> {
+    let mut reader = kernel::logger::DmesgReader::from_start();
+    core::iter::from_fn(|| reader.read()).collect()
 }
 pub static SCREEN_SIZE_PIXELS: OnceCell<Vec2> = OnceCell::uninit();
 pub fn run_app(mut app: AppType) {
@@ -60,13 +95,29 @@ static FOCUSED_APP: Mutex<Option<AppType>> = Mutex::new(None);
 
 pub static ON_LOG_LISTENERS: Mutex<Vec<Mutex<&mut Box<dyn App + Send>>>> = Mutex::new(Vec::new());
 
-pub fn on_log(log: &[u8; MAX_LOG_SIZE]) {
+pub fn on_log(log: &LogRecord) {
     let listeners = ON_LOG_LISTENERS.lock();
     for app in listeners.iter() {
         app.lock().on_log(log);
     }
 }
 
+/// How often `App::on_time` is called
+pub const ON_TIME_PERIOD: Duration = Duration::from_millis(100);
+
+async fn run_on_time_loop() {
+    let mut interval = kernel::time::interval(ON_TIME_PERIOD);
+    // apps only care about the current state, catching up on missed calls is useless
+    interval.set_missed_tick_behavior(kernel::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let mut lock = FOCUSED_APP.lock();
+        if let Some(ref mut app) = *lock {
+            app.on_time();
+        }
+    }
+}
+
 pub fn on_key_pressed(key: &DecodedKey) {
     let mut lock = FOCUSED_APP.lock();
     if let Some(ref mut app) = *lock {
@@ -74,13 +125,13 @@ pub fn on_key_pressed(key: &DecodedKey) {
     }
 }
 
-pub const MAX_LOG_SIZE: usize = kernel::logger::MAX_LOG_SIZE;
 pub trait App {
     fn on_key_pressed(&mut self, key: &DecodedKey);
+    /// Called every `ON_TIME_PERIOD` while the app is focused
     fn on_time(&mut self);
     fn init(&mut self, graphics_data: WindowSettings);
 
-    fn on_log(&mut self, log: &[u8; MAX_LOG_SIZE]);
+    fn on_log(&mut self, log: &LogRecord);
 }
 
 pub fn shutdown() {
@@ -1,28 +1,143 @@
-fn main() {
+mod options;
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, ExitCode},
+    time::{Duration, Instant},
+};
+
+use bootloader::DiskImageBuilder;
+use options::{Firmware, Options, USAGE};
+
+// isa-debug-exit turns the value written by `kernel::qemu::exit_qemu` into `(value << 1) | 1`
+const TESTS_PASSED: i32 = (0x10 << 1) | 1;
+// the kernel also times out single tests, this catches hangs during boot
+const TEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+fn main() -> ExitCode {
+    let mut options = match Options::parse(std::env::args_os().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    if options.help {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+
+    // `cargo test` in `kernel/` passes the test kernel (see `kernel/.cargo/config.toml`)
+    if let Some(test_kernel) = options.test_kernel.clone() {
+        options.firmware = Firmware::Bios;
+        options.headless = true;
+        options.timeout = options.timeout.or(Some(TEST_TIMEOUT));
+        let image = test_kernel.with_extension("img");
+        create_disk_image(&test_kernel, Firmware::Bios, None, &image);
+        return match run_qemu(&options, &image) {
+            Some(TESTS_PASSED) => ExitCode::SUCCESS,
+            code => {
+                eprintln!("tests failed, qemu exit code: {code:?}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
     // read env variables that were set in build script
-    let uefi_path = env!("UEFI_PATH");
-    let bios_path = env!("BIOS_PATH");
+    let image = PathBuf::from(match options.firmware {
+        Firmware::Uefi => env!("UEFI_PATH"),
+        Firmware::Bios => env!("BIOS_PATH"),
+    });
+    // the command line goes into the ramdisk, so the image has to be created again
+    let image = match options.kernel_cmdline() {
+        Some(cmdline) => {
+            let ramdisk = image.with_file_name("cmdline.txt");
+            std::fs::write(&ramdisk, cmdline).expect("failed to write the kernel command line");
+            let image_with_cmdline = image.with_extension("cmdline.img");
+            create_disk_image(
+                Path::new(env!("KERNEL_PATH")),
+                options.firmware,
+                Some(&ramdisk),
+                &image_with_cmdline,
+            );
+            image_with_cmdline
+        }
+        None => image,
+    };
+
+    match run_qemu(&options, &image) {
+        Some(0) => ExitCode::SUCCESS,
+        Some(code) => ExitCode::from(code as u8),
+        None => ExitCode::FAILURE,
+    }
+}
 
-    // choose whether to start the UEFI or BIOS image
-    let uefi = true;
+fn create_disk_image(kernel: &Path, firmware: Firmware, ramdisk: Option<&Path>, image: &Path) {
+    let mut builder = DiskImageBuilder::new(kernel.to_path_buf());
+    if let Some(ramdisk) = ramdisk {
+        builder.set_ramdisk(ramdisk.to_path_buf());
+    }
+    match firmware {
+        Firmware::Uefi => builder.create_uefi_image(image),
+        Firmware::Bios => builder.create_bios_image(image),
+    }
+    .expect("failed to create a disk image");
+}
 
-    let mut cmd = std::process::Command::new("qemu-system-x86_64");
-    if uefi {
+/// Returns the exit code of QEMU, `None` when it was killed (eg. by the timeout)
+fn run_qemu(options: &Options, image: &Path) -> Option<i32> {
+    let mut cmd = Command::new("qemu-system-x86_64");
+    if options.firmware == Firmware::Uefi {
         cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
+    }
+    cmd.arg("-drive")
+        .arg(format!("format=raw,file={}", image.display()));
+    for disk in &options.disks {
         cmd.arg("-drive")
-            .arg(format!("format=raw,file={uefi_path}"));
-    } else {
-        cmd.arg("-drive")
-            .arg(format!("format=raw,file={bios_path}"));
+            .arg(format!("format=raw,file={}", disk.display()));
     }
+
+    // COM1, logs and the serial console
     cmd.arg("-serial").arg("stdio");
+    // COM2, the kernel's gdb stub
+    if let Some(port) = options.gdb_stub_port {
+        cmd.arg("-serial").arg(format!("tcp::{port},server,nowait"));
+    }
+    if options.headless {
+        cmd.arg("-display").arg("none");
+    }
 
     cmd.arg("-device")
         .arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
 
-    cmd.arg("-smp").arg("4");
-    // cmd.arg("-d").arg("int");
-    // cmd.arg("-D").arg("qemu.log");
-    let mut child = cmd.spawn().unwrap();
-    child.wait().unwrap();
+    cmd.arg("-smp").arg(options.cpus.to_string());
+    if let Some(memory) = &options.memory {
+        cmd.arg("-m").arg(memory);
+    }
+    if options.gdb {
+        // `target remote :1234`, the cpus wait until gdb continues them
+        cmd.arg("-s").arg("-S");
+    }
+    if let Some(flags) = &options.qemu_log {
+        cmd.arg("-d").arg(flags);
+        cmd.arg("-D").arg(&options.qemu_log_file);
+    }
+
+    let mut child = cmd.spawn().expect("failed to start qemu");
+    let Some(timeout) = options.timeout else {
+        return child.wait().expect("failed to wait for qemu").code();
+    };
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().expect("failed to wait for qemu") {
+            return status.code();
+        }
+        if start.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            eprintln!("qemu timed out after {timeout:?}");
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
 }
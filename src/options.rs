@@ -0,0 +1,244 @@
+use std::{ffi::OsString, path::PathBuf, time::Duration};
+
+pub const USAGE: &str = "\
+usage: cargo run -- [options] [test kernel]
+
+options:
+  --bios                 boot the BIOS image instead of UEFI
+  --uefi                 boot the UEFI image (default)
+  --cpus <n>             number of cpus (default: 4)
+  --mem <size>           memory size, eg. 512M or 2G (default: QEMU's)
+  --headless             no display, serial output only
+  --gdb                  start QEMU's gdb server on :1234 and wait for gdb before booting
+  --gdb-stub <port>      connect the kernel's gdb stub (COM2) to tcp port <port>
+  --disk <path>          attach an extra raw disk image, can be repeated
+  --cmdline <options>    kernel boot options, override KERNEL_CMDLINE, eg. \"log=debug smp=off\"
+  --qemu-log <flags>     QEMU debug log flags (-d), eg. int,cpu_reset
+  --qemu-log-file <path> where the QEMU log goes (default: qemu.log)
+  --timeout <seconds>    kill QEMU after this long and fail
+  -h, --help             print this help
+
+A test kernel (passed by `cargo test` in kernel/) is booted headless with BIOS,
+its exit code tells whether the tests passed.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Firmware {
+    Uefi,
+    Bios,
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub firmware: Firmware,
+    pub cpus: u32,
+    pub memory: Option<String>,
+    pub headless: bool,
+    pub gdb: bool,
+    pub gdb_stub_port: Option<u16>,
+    pub disks: Vec<PathBuf>,
+    pub cmdline: Option<String>,
+    pub qemu_log: Option<String>,
+    pub qemu_log_file: PathBuf,
+    pub timeout: Option<Duration>,
+    pub test_kernel: Option<PathBuf>,
+    pub help: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            firmware: Firmware::Uefi,
+            cpus: 4,
+            memory: None,
+            headless: false,
+            gdb: false,
+            gdb_stub_port: None,
+            disks: Vec::new(),
+            cmdline: None,
+            qemu_log: None,
+            qemu_log_file: PathBuf::from("qemu.log"),
+            timeout: None,
+            test_kernel: None,
+            help: false,
+        }
+    }
+}
+
+impl Options {
+    /// Accepts both `--name value` and `--name=value`
+    pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let arg = arg
+                .into_string()
+                .map_err(|arg| format!("argument is not valid UTF-8: {arg:?}"))?;
+            if !arg.starts_with('-') {
+                if options.test_kernel.is_some() {
+                    return Err(format!("unexpected argument: {arg}"));
+                }
+                options.test_kernel = Some(PathBuf::from(arg));
+                continue;
+            }
+
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let mut value = || -> Result<String, String> {
+                match inline_value.clone() {
+                    Some(value) => Ok(value),
+                    None => args
+                        .next()
+                        .and_then(|value| value.into_string().ok())
+                        .ok_or_else(|| format!("{name} needs a value")),
+                }
+            };
+
+            match name.as_str() {
+                "--bios" => options.firmware = Firmware::Bios,
+                "--uefi" => options.firmware = Firmware::Uefi,
+                "--cpus" => {
+                    let cpus = value()?;
+                    options.cpus = match cpus.parse() {
+                        Ok(cpus) if cpus > 0 => cpus,
+                        _ => return Err(format!("invalid cpu count: {cpus}")),
+                    };
+                }
+                "--mem" => options.memory = Some(value()?),
+                "--headless" => options.headless = true,
+                "--gdb" => options.gdb = true,
+                "--gdb-stub" => {
+                    let port = value()?;
+                    let port = port.parse().map_err(|_| format!("invalid port: {port}"))?;
+                    options.gdb_stub_port = Some(port);
+                }
+                "--disk" => options.disks.push(PathBuf::from(value()?)),
+                "--cmdline" => options.cmdline = Some(value()?),
+                "--qemu-log" => options.qemu_log = Some(value()?),
+                "--qemu-log-file" => options.qemu_log_file = PathBuf::from(value()?),
+                "--timeout" => {
+                    let seconds = value()?;
+                    let seconds: u64 = seconds
+                        .parse()
+                        .map_err(|_| format!("invalid timeout: {seconds}"))?;
+                    options.timeout = Some(Duration::from_secs(seconds));
+                }
+                "-h" | "--help" => options.help = true,
+                _ => return Err(format!("unknown option: {name}")),
+            }
+        }
+        // qemu would wait for gdb on a port the kernel never answers
+        let gdb_off = options
+            .cmdline
+            .iter()
+            .flat_map(|cmdline| cmdline.split_whitespace())
+            .any(|option| option == "gdb=off");
+        if options.gdb_stub_port.is_some() && gdb_off {
+            return Err("--gdb-stub can't be used with gdb=off in --cmdline".to_string());
+        }
+        Ok(options)
+    }
+
+    /// Command line for the kernel, the gdb stub has to be enabled in it for `--gdb-stub`
+    pub fn kernel_cmdline(&self) -> Option<String> {
+        let mut cmdline = self.cmdline.clone();
+        if self.gdb_stub_port.is_some() {
+            let cmdline = cmdline.get_or_insert_default();
+            if !cmdline
+                .split_whitespace()
+                .any(|option| option == "gdb" || option.starts_with("gdb="))
+            {
+                cmdline.push_str(" gdb");
+            }
+        }
+        cmdline.map(|cmdline| cmdline.trim().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn inline_and_separate_values() {
+        let options =
+            parse(&["--cpus=2", "--mem", "1G", "--disk=a.img", "--disk", "b.img"]).unwrap();
+        assert_eq!(options.cpus, 2);
+        assert_eq!(options.memory.as_deref(), Some("1G"));
+        assert_eq!(
+            options.disks,
+            [PathBuf::from("a.img"), PathBuf::from("b.img")]
+        );
+        // only the first `=` splits, the rest belongs to the value
+        let options = parse(&["--cmdline=log=debug smp=off"]).unwrap();
+        assert_eq!(options.cmdline.as_deref(), Some("log=debug smp=off"));
+    }
+
+    #[test]
+    fn missing_value() {
+        assert_eq!(parse(&["--mem"]).unwrap_err(), "--mem needs a value");
+        assert_eq!(
+            parse(&["--bios", "--timeout"]).unwrap_err(),
+            "--timeout needs a value"
+        );
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert_eq!(parse(&["--cpus", "0"]).unwrap_err(), "invalid cpu count: 0");
+        assert_eq!(
+            parse(&["--cpus=many"]).unwrap_err(),
+            "invalid cpu count: many"
+        );
+        assert!(parse(&["--gdb-stub", "70000"]).is_err());
+        assert!(parse(&["--timeout=soon"]).is_err());
+        assert!(parse(&["--fast"]).is_err());
+    }
+
+    #[test]
+    fn one_positional_argument() {
+        let options = parse(&["--bios", "target/test-kernel"]).unwrap();
+        assert_eq!(
+            options.test_kernel,
+            Some(PathBuf::from("target/test-kernel"))
+        );
+        assert_eq!(options.firmware, Firmware::Bios);
+        assert_eq!(
+            parse(&["kernel-a", "kernel-b"]).unwrap_err(),
+            "unexpected argument: kernel-b"
+        );
+    }
+
+    #[test]
+    fn gdb_stub_enables_gdb_in_cmdline() {
+        assert_eq!(parse(&[]).unwrap().kernel_cmdline(), None);
+        let cmdline = |args: &[&str]| parse(args).unwrap().kernel_cmdline();
+        assert_eq!(cmdline(&["--gdb-stub", "1235"]).as_deref(), Some("gdb"));
+        assert_eq!(
+            cmdline(&["--gdb-stub=1235", "--cmdline", "log=debug"]).as_deref(),
+            Some("log=debug gdb")
+        );
+        assert_eq!(
+            cmdline(&["--gdb-stub=1235", "--cmdline", "gdb=wait"]).as_deref(),
+            Some("gdb=wait")
+        );
+        assert_eq!(
+            cmdline(&["--gdb-stub=1235", "--cmdline=gdb smp=off"]).as_deref(),
+            Some("gdb smp=off")
+        );
+        // the stub would be disabled while qemu still gives it the port
+        assert!(parse(&["--gdb-stub", "1235", "--cmdline", "gdb=off"]).is_err());
+        assert!(parse(&["--cmdline", "gdb=off", "--gdb-stub=1235"]).is_err());
+        // `gdb` is only added for the stub
+        assert_eq!(
+            cmdline(&["--cmdline", "smp=off"]).as_deref(),
+            Some("smp=off")
+        );
+    }
+}
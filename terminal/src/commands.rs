@@ -1,41 +1,86 @@
-use core::str::FromStr;
-
 use alloc::{
     collections::btree_map::BTreeMap,
     string::{String, ToString},
     vec::Vec,
 };
-use log::*;
+use core::fmt::Write;
 
 use crate::Terminal;
-fn get_first_arg<T: FromStr + Default>(args: Vec<&str>) -> T {
-    match args.get(0) {
-        Some(val) => match val.parse::<T>() {
-            Ok(parsed) => parsed,
+
+/// `output` is where the command prints, eg. the serial console. Not logs, so they aren't
+/// filtered or kept in dmesg.
+pub type OnCommandFunction = fn(&mut Terminal, Vec<&str>, &mut dyn Write);
+// `logs true|false` shows or hides logs in the terminal,
+// `logs kernel::memory=warn,debug` changes log levels, `logs` prints the current ones
+fn set_log_level(terminal: &mut Terminal, args: Vec<&str>, output: &mut dyn Write) {
+    let Some(arg) = args.first() else {
+        let _ = writeln!(output, "log levels: {}", os::log_filter());
+        return;
+    };
+    if let Ok(logs) = arg.parse::<bool>() {
+        terminal.logs = logs;
+        let _ = writeln!(output, "logs are set to: {}", terminal.logs);
+        return;
+    }
+    let _ = match os::apply_log_directives(arg) {
+        Ok(()) => writeln!(output, "log levels: {}", os::log_filter()),
+        Err(err) => writeln!(output, "invalid log directives {arg:?}: {err:?}"),
+    };
+}
+// like `top`, but for async tasks and printed once
+fn print_tasks(_: &mut Terminal, _: Vec<&str>, output: &mut dyn Write) {
+    let mut tasks = os::task_list();
+    tasks.sort_by_key(|task| core::cmp::Reverse(task.poll_time_us));
+
+    let _ = writeln!(
+        output,
+        "  id | priority            | cpu | polls    | poll time us | woken us ago | name"
+    );
+    for task in tasks {
+        let cpu = match task.running_on {
+            Some(index) => index.to_string(),
+            None => "-".to_string(),
+        };
+        let _ = writeln!(
+            output,
+            "{:>4} | {:<19} | {:>3} | {:>8} | {:>12} | {:>12} | {}",
+            task.id.as_u64(),
+            alloc::format!("{:?}", task.priority),
+            cpu,
+            task.poll_count,
+            task.poll_time_us,
+            task.since_last_wake_us,
+            task.name
+        );
+    }
+}
+// logs kept since boot, `dmesg <count>` prints only the newest ones
+fn print_dmesg(_: &mut Terminal, args: Vec<&str>, output: &mut dyn Write) {
+    let records = os::dmesg();
+    let count = match args.first() {
+        Some(arg) => match arg.parse() {
+            Ok(count) => count,
             Err(_) => {
-                warn!("failed to parse value to target type, setting default value");
-                T::default()
+                let _ = writeln!(output, "dmesg: invalid count {arg:?}, expected a number");
+                return;
             }
         },
-        None => {
-            warn!("value was not specified! setting default value");
-            T::default()
-        }
+        None => records.len(),
+    };
+    // not logged again, that would put copies of them into dmesg
+    for record in &records[records.len().saturating_sub(count)..] {
+        let _ = writeln!(output, "{record}");
     }
 }
-
-pub type OnCommandFunction = fn(&mut Terminal, Vec<&str>);
-fn set_log_level(terminal: &mut Terminal, args: Vec<&str>) {
-    terminal.logs = get_first_arg(args);
-    debug!("logs are set to: {}", terminal.logs)
-}
 pub fn init_commands() -> BTreeMap<String, OnCommandFunction> {
     BTreeMap::from([
         (
             "poweroff".to_string(),
-            (|_, _| os::shutdown()) as OnCommandFunction,
+            (|_, _, _| os::shutdown()) as OnCommandFunction,
         ),
         ("logs".to_string(), set_log_level as OnCommandFunction),
+        ("top".to_string(), print_tasks as OnCommandFunction),
+        ("dmesg".to_string(), print_dmesg as OnCommandFunction),
         // (
         //     "disable-pic".to_string(),
         //     (|_, _| kernel::interrupts::disable_pic()) as OnCommandFunction,
@@ -45,11 +90,18 @@ pub fn init_commands() -> BTreeMap<String, OnCommandFunction> {
 impl Terminal {
     pub fn parse_and_run_current_command(&mut self) {
         let current_str_input = self.current_str_input.clone();
-        let mut split = current_str_input.split(" ");
+        // nothing is drawn on screen yet, so the output goes to serial
+        let mut output = String::new();
+        self.run_command(&current_str_input, &mut output);
+        os::serial_print(format_args!("{output}"));
+    }
+    /// `line` is the command name followed by its arguments, separated by spaces
+    pub fn run_command(&mut self, line: &str, output: &mut dyn Write) {
+        let mut split = line.split(" ");
         let command_name = match split.next() {
             Some(str) => str,
             None => {
-                warn!("you need to specify command name!");
+                let _ = writeln!(output, "you need to specify command name!");
                 return;
             }
         };
@@ -57,10 +109,11 @@ impl Terminal {
         let args: Vec<&str> = split.collect();
         match self.commands.get(command_name) {
             Some(command_function) => {
-                command_function(self, args);
+                command_function(self, args, output);
             }
             None => {
-                warn!(
+                let _ = writeln!(
+                    output,
                     "no command with name: { } was found, all commands: {:?}",
                     command_name,
                     self.commands.keys()
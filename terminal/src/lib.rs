@@ -1,7 +1,12 @@
 #![no_std]
 #![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 mod commands;
+mod line_editor;
+mod serial_console;
 
 extern crate alloc;
 
@@ -19,6 +24,15 @@ use os::graphics::{text::font_constants::CHAR_RASTER_HEIGHT, *};
 use pc_keyboard::DecodedKey;
 use spin::Mutex;
 
+pub use serial_console::run_serial_console;
+
+#[cfg(test)]
+bootloader_api::entry_point!(test_kernel_main, config = &kernel::BOOTLOADER_CONFIG);
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    kernel::testing::run(boot_info, test_main);
+}
+
 pub struct Terminal {
     window_settings: WindowSettings,
     current_str_input: String,
@@ -44,7 +58,7 @@ impl Terminal {
             y: 0,
         }
     }
-    fn on_log(&mut self, log_data: &[u8; 80]) {}
+    fn on_log(&mut self, log: &kernel::logger::LogRecord) {}
 }
 // converts rows/columns to pixels
 fn char_pos(x: u16, y: u16) -> Vec2 {
@@ -160,9 +174,7 @@ impl os::App for Terminal {
         }
     }
 
-    fn on_time(&mut self) {
-        todo!()
-    }
+    fn on_time(&mut self) {}
 
     fn init(&mut self, graphics_data: WindowSettings) {
         // let app = Box::new(self) as os::AppType;
@@ -191,7 +203,7 @@ impl os::App for Terminal {
         );
     }
 
-    fn on_log(&mut self, log: &[u8; os::MAX_LOG_SIZE]) {
+    fn on_log(&mut self, log: &kernel::logger::LogRecord) {
         todo!()
     }
 }
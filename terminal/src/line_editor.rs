@@ -0,0 +1,288 @@
+use alloc::{
+    collections::vec_deque::VecDeque,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::Write;
+
+const HISTORY_SIZE: usize = 32;
+
+// bytes sent by VT100 compatible terminals
+const CTRL_A: u8 = 0x01;
+const CTRL_C: u8 = 0x03;
+const CTRL_E: u8 = 0x05;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const ESC: u8 = 0x1b;
+const DELETE: u8 = 0x7f;
+
+enum Escape {
+    None,
+    // got ESC
+    Started,
+    // got ESC [, with the number that came after it (eg. `3` in `ESC [ 3 ~`)
+    Csi(u8),
+}
+
+/// Edits a single line of text received byte by byte from a serial terminal.
+/// Supports moving the cursor (arrows, home/end, ctrl+a/e), backspace/delete, ctrl+u to clear
+/// the line, ctrl+c to drop it and command history (up/down).
+pub struct LineEditor {
+    prompt: &'static str,
+    line: Vec<char>,
+    cursor: usize,
+    history: VecDeque<String>,
+    // how far back in history, `None` while editing a new line
+    history_index: Option<usize>,
+    escape: Escape,
+    // so `\r\n` is one enter, not two
+    last_was_cr: bool,
+}
+
+impl LineEditor {
+    pub fn new(prompt: &'static str) -> Self {
+        LineEditor {
+            prompt,
+            line: Vec::new(),
+            cursor: 0,
+            history: VecDeque::new(),
+            history_index: None,
+            escape: Escape::None,
+            last_was_cr: false,
+        }
+    }
+
+    pub fn prompt(&self) -> &'static str {
+        self.prompt
+    }
+
+    /// Handles one received byte, appends what should be sent back to the terminal to `echo`.
+    /// Returns the line once enter is pressed.
+    pub fn push_byte(&mut self, byte: u8, echo: &mut String) -> Option<String> {
+        let last_was_cr = core::mem::replace(&mut self.last_was_cr, byte == b'\r');
+
+        match self.escape {
+            Escape::Started => {
+                self.escape = match byte {
+                    b'[' => Escape::Csi(0),
+                    _ => Escape::None,
+                };
+                return None;
+            }
+            Escape::Csi(parameter) => {
+                self.escape = Escape::None;
+                match byte {
+                    b'0'..=b'9' => {
+                        self.escape =
+                            Escape::Csi(parameter.saturating_mul(10).saturating_add(byte - b'0'));
+                    }
+                    b'A' => self.history_up(echo),
+                    b'B' => self.history_down(echo),
+                    b'C' => self.move_cursor(self.cursor + 1, echo),
+                    b'D' => self.move_cursor(self.cursor.saturating_sub(1), echo),
+                    b'H' => self.move_cursor(0, echo),
+                    b'F' => self.move_cursor(self.line.len(), echo),
+                    b'~' => match parameter {
+                        1 | 7 => self.move_cursor(0, echo),
+                        4 | 8 => self.move_cursor(self.line.len(), echo),
+                        3 => self.delete(echo),
+                        _ => {}
+                    },
+                    _ => {}
+                }
+                return None;
+            }
+            Escape::None => {}
+        }
+
+        match byte {
+            b'\n' if last_was_cr => {}
+            b'\r' | b'\n' => {
+                echo.push_str("\r\n");
+                return Some(self.finish_line());
+            }
+            ESC => self.escape = Escape::Started,
+            BACKSPACE | DELETE => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.delete(echo);
+                }
+            }
+            CTRL_A => self.move_cursor(0, echo),
+            CTRL_E => self.move_cursor(self.line.len(), echo),
+            CTRL_C => {
+                echo.push_str("^C\r\n");
+                echo.push_str(self.prompt);
+                self.line.clear();
+                self.cursor = 0;
+                self.history_index = None;
+            }
+            CTRL_U => {
+                self.line.clear();
+                self.cursor = 0;
+                self.redraw(echo);
+            }
+            // printable ascii, anything else is ignored
+            0x20..=0x7e => self.insert(byte as char, echo),
+            _ => {}
+        }
+        None
+    }
+
+    fn finish_line(&mut self) -> String {
+        let line: String = self.line.drain(..).collect();
+        self.cursor = 0;
+        self.history_index = None;
+
+        let line = line.trim().to_string();
+        if !line.is_empty() && self.history.back() != Some(&line) {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+
+    fn insert(&mut self, char: char, echo: &mut String) {
+        self.line.insert(self.cursor, char);
+        self.cursor += 1;
+        if self.cursor == self.line.len() {
+            // appending is the common case, no need to redraw
+            echo.push(char);
+        } else {
+            self.redraw(echo);
+        }
+    }
+
+    /// Removes the char under the cursor
+    fn delete(&mut self, echo: &mut String) {
+        if self.cursor < self.line.len() {
+            self.line.remove(self.cursor);
+            self.redraw(echo);
+        }
+    }
+
+    fn move_cursor(&mut self, cursor: usize, echo: &mut String) {
+        let cursor = cursor.min(self.line.len());
+        if cursor < self.cursor {
+            let _ = write!(echo, "\x1b[{}D", self.cursor - cursor);
+        } else if cursor > self.cursor {
+            let _ = write!(echo, "\x1b[{}C", cursor - self.cursor);
+        }
+        self.cursor = cursor;
+    }
+
+    fn history_up(&mut self, echo: &mut String) {
+        let index = self.history_index.map_or(0, |index| index + 1);
+        if index < self.history.len() {
+            self.show_history(Some(index), echo);
+        }
+    }
+    fn history_down(&mut self, echo: &mut String) {
+        match self.history_index {
+            Some(0) | None => self.show_history(None, echo),
+            Some(index) => self.show_history(Some(index - 1), echo),
+        }
+    }
+    fn show_history(&mut self, index: Option<usize>, echo: &mut String) {
+        self.history_index = index;
+        self.line = match index {
+            Some(index) => self.history[self.history.len() - 1 - index]
+                .chars()
+                .collect(),
+            None => Vec::new(),
+        };
+        self.cursor = self.line.len();
+        self.redraw(echo);
+    }
+
+    /// Prints the whole line again, clears whatever was after it and puts the cursor back
+    fn redraw(&self, echo: &mut String) {
+        echo.push('\r');
+        echo.push_str(self.prompt);
+        echo.extend(self.line.iter());
+        echo.push_str("\x1b[K");
+        let chars_after_cursor = self.line.len() - self.cursor;
+        if chars_after_cursor > 0 {
+            let _ = write!(echo, "\x1b[{chars_after_cursor}D");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the finished lines and everything echoed
+    fn type_bytes(editor: &mut LineEditor, bytes: &[u8]) -> (Vec<String>, String) {
+        let mut echo = String::new();
+        let lines = bytes
+            .iter()
+            .filter_map(|byte| editor.push_byte(*byte, &mut echo))
+            .collect();
+        (lines, echo)
+    }
+
+    #[test_case]
+    fn crlf_is_one_enter() {
+        let mut editor = LineEditor::new("> ");
+        let (lines, echo) = type_bytes(&mut editor, b"ls\r\ntop\n\r");
+        assert_eq!(lines, ["ls", "top", ""]);
+        assert_eq!(echo, "ls\r\ntop\r\n\r\n");
+    }
+
+    #[test_case]
+    fn arrows_move_the_cursor() {
+        let mut editor = LineEditor::new("> ");
+        let (_, echo) = type_bytes(&mut editor, b"ac\x1b[D");
+        assert!(echo.ends_with("\x1b[1D"));
+        let (lines, _) = type_bytes(&mut editor, b"b\x1b[C\x1b[Cd\r");
+        assert_eq!(lines, ["abcd"]);
+    }
+
+    #[test_case]
+    fn tilde_sequences() {
+        let mut editor = LineEditor::new("> ");
+        // home, delete the char under the cursor, end
+        let (lines, _) = type_bytes(&mut editor, b"xabc\x1b[1~\x1b[3~\x1b[4~d\r");
+        assert_eq!(lines, ["abcd"]);
+        let (lines, _) = type_bytes(&mut editor, b"bc\x1b[7~a\x1b[8~d\x1b[5~\r");
+        assert_eq!(lines, ["abcd"]);
+    }
+
+    #[test_case]
+    fn backspace_deletes_before_the_cursor() {
+        let mut editor = LineEditor::new("> ");
+        let (_, echo) = type_bytes(&mut editor, b"abxc\x1b[D\x08");
+        assert_eq!(editor.line.iter().collect::<String>(), "abc");
+        assert_eq!(editor.cursor, 2);
+        // redrawn from the prompt, the cursor goes back before `c`
+        assert!(echo.ends_with("\r> abc\x1b[K\x1b[1D"));
+
+        let (lines, _) = type_bytes(&mut editor, b"\x01\x08\x7f\r");
+        assert_eq!(lines, ["abc"]);
+    }
+
+    #[test_case]
+    fn history_up_and_down() {
+        let mut editor = LineEditor::new("> ");
+        type_bytes(&mut editor, b"one\rtwo\rtwo\r");
+        assert_eq!(editor.history, ["one", "two"]);
+
+        let up = b"\x1b[A";
+        let down = b"\x1b[B";
+        type_bytes(&mut editor, up);
+        assert_eq!(editor.line.iter().collect::<String>(), "two");
+        type_bytes(&mut editor, up);
+        type_bytes(&mut editor, up);
+        assert_eq!(editor.line.iter().collect::<String>(), "one");
+        type_bytes(&mut editor, down);
+        assert_eq!(editor.line.iter().collect::<String>(), "two");
+        type_bytes(&mut editor, down);
+        assert!(editor.line.is_empty());
+
+        let (lines, _) = type_bytes(&mut editor, b"\x1b[A\x1b[A!\r");
+        assert_eq!(lines, ["one!"]);
+    }
+}
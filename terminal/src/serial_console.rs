@@ -0,0 +1,35 @@
+use alloc::string::String;
+
+use crate::{Terminal, line_editor::LineEditor};
+
+const PROMPT: &str = "> ";
+
+/// Runs terminal commands typed over the serial line, so it can be used without a display
+/// (eg. `-serial stdio` in QEMU). Command output goes through the same transmit queue as the
+/// echo and the prompt, so they can't interleave, and log levels don't hide it.
+pub async fn run_serial_console() {
+    // never drawn, only runs the commands
+    let mut terminal = Terminal::new();
+    let mut editor = LineEditor::new(PROMPT);
+    let mut input = os::serial_input();
+
+    os::serial_write(editor.prompt().as_bytes()).await;
+    loop {
+        let byte = input.read_byte().await;
+
+        let mut echo = String::new();
+        let line = editor.push_byte(byte, &mut echo);
+        os::serial_write(echo.as_bytes()).await;
+
+        let Some(line) = line else {
+            continue;
+        };
+        if !line.is_empty() {
+            let mut output = String::new();
+            terminal.run_command(&line, &mut output);
+            // the terminal is in raw mode, `\n` alone only moves down
+            os::serial_write(output.replace('\n', "\r\n").as_bytes()).await;
+        }
+        os::serial_write(editor.prompt().as_bytes()).await;
+    }
+}